@@ -0,0 +1,35 @@
+use std::process::Command;
+
+/// Passes the git commit and build time to the crate as `GIT_HASH` and `BUILD_TIMESTAMP`, for the
+/// `/version` endpoint.
+fn main() {
+    // Builds outside of a git checkout, e.g. from a source tarball, can set it themselves.
+    let git_hash = std::env::var("GIT_HASH").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!(
+        "cargo:rustc-env=GIT_HASH={}",
+        git_hash.as_deref().unwrap_or("unknown")
+    );
+
+    // Seconds since the Unix epoch. Honors SOURCE_DATE_EPOCH for reproducible builds.
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+            .to_string()
+    });
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={timestamp}");
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
@@ -0,0 +1,162 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use axum::{
+    body::Body,
+    http::{header, Request, Response, StatusCode},
+    response::IntoResponse,
+    routing::MethodRouter,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use subtle::ConstantTimeEq;
+use tower_http::validate_request::{ValidateRequest, ValidateRequestHeaderLayer};
+
+/// A configured secret. Kept out of `Debug` output so it doesn't end up in the startup log.
+#[derive(Clone)]
+pub struct Secret(Arc<str>);
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.into()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Secret {
+    /// Compares against `other` in constant time, so the comparison doesn't leak how much of the
+    /// secret was guessed correctly.
+    pub fn matches(&self, other: &str) -> bool {
+        self.0.as_bytes().ct_eq(other.as_bytes()).into()
+    }
+
+    /// The raw secret, for using it as a key.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// Checks for an `Authorization: Bearer <token>` header matching the configured token.
+#[derive(Clone, Debug)]
+pub struct BearerAuth {
+    token: Secret,
+}
+
+impl<B> ValidateRequest<B> for BearerAuth {
+    type ResponseBody = Body;
+
+    fn validate(&mut self, request: &mut Request<B>) -> Result<(), Response<Body>> {
+        let provided = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+
+        match provided {
+            Some(token) if self.token.matches(token) => Ok(()),
+            _ => {
+                tracing::warn!("Rejecting request with missing or invalid bearer token");
+                Err(StatusCode::UNAUTHORIZED.into_response())
+            }
+        }
+    }
+}
+
+pub fn bearer_layer(token: Secret) -> ValidateRequestHeaderLayer<BearerAuth> {
+    ValidateRequestHeaderLayer::custom(BearerAuth { token })
+}
+
+/// Username and password for HTTP Basic auth, which browsers can ask for on their own.
+#[derive(knuffel::Decode, Clone, Debug)]
+pub struct BasicAuthConfig {
+    #[knuffel(child, unwrap(argument))]
+    username: String,
+    #[knuffel(child, unwrap(argument, str))]
+    password: Secret,
+}
+
+/// Checks for an `Authorization: Basic <credentials>` header matching the configured credentials,
+/// and otherwise asks for them with a `WWW-Authenticate` challenge.
+#[derive(Clone, Debug)]
+pub struct BasicAuth {
+    credentials: BasicAuthConfig,
+}
+
+impl<B> ValidateRequest<B> for BasicAuth {
+    type ResponseBody = Body;
+
+    fn validate(&mut self, request: &mut Request<B>) -> Result<(), Response<Body>> {
+        let provided = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "))
+            .and_then(|v| BASE64_STANDARD.decode(v).ok())
+            .and_then(|v| String::from_utf8(v).ok());
+
+        let valid = provided
+            .as_deref()
+            .and_then(|v| v.split_once(':'))
+            .is_some_and(|(username, password)| {
+                let username_matches: bool = username
+                    .as_bytes()
+                    .ct_eq(self.credentials.username.as_bytes())
+                    .into();
+                // Not short-circuiting, so a wrong username takes as long as a wrong password.
+                username_matches & self.credentials.password.matches(password)
+            });
+
+        if valid {
+            return Ok(());
+        }
+        tracing::warn!("Rejecting request with missing or invalid basic auth credentials");
+        Err((
+            StatusCode::UNAUTHORIZED,
+            [(
+                header::WWW_AUTHENTICATE,
+                r#"Basic realm="reasonable-excuse", charset="UTF-8""#,
+            )],
+        )
+            .into_response())
+    }
+}
+
+/// How the protected routes of a module are authenticated.
+#[derive(Clone, Debug)]
+pub enum Auth {
+    Open,
+    Bearer(Secret),
+    Basic(BasicAuthConfig),
+}
+
+impl Auth {
+    /// Uses whichever of a bearer token or basic auth credentials is configured. Configuring both
+    /// is an error.
+    pub fn new(token: Option<&Secret>, basic: Option<&BasicAuthConfig>) -> miette::Result<Self> {
+        match (token, basic) {
+            (Some(_), Some(_)) => miette::bail!("Only one of auth-token and basic-auth may be set"),
+            (Some(token), None) => Ok(Auth::Bearer(token.clone())),
+            (None, Some(basic)) => Ok(Auth::Basic(basic.clone())),
+            (None, None) => Ok(Auth::Open),
+        }
+    }
+}
+
+/// Requires the configured authentication for `route`, if any.
+pub fn protect<S>(route: MethodRouter<S>, auth: &Auth) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    match auth {
+        Auth::Open => route,
+        Auth::Bearer(token) => route.layer(bearer_layer(token.clone())),
+        Auth::Basic(credentials) => route.layer(ValidateRequestHeaderLayer::custom(BasicAuth {
+            credentials: credentials.clone(),
+        })),
+    }
+}
@@ -1,82 +1,744 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use arc_swap::ArcSwap;
 use axum::{
     extract::{ConnectInfo, Query},
-    http::StatusCode,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     Extension, Router,
 };
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
 use miette::{Context, IntoDiagnostic};
 use regex::Regex;
 use reqwest::{Client, Url};
+use tokio::sync::RwLock;
+
+use crate::{auth::Secret, error::ApiError, http_client, ics};
 
 #[derive(knuffel::Decode, Debug)]
 pub struct Config {
+    /// When false, the calendar is left out entirely.
+    #[knuffel(child, unwrap(argument), default = true)]
+    enabled: bool,
     #[knuffel(child, unwrap(argument))]
     route: String,
-    #[knuffel(child, unwrap(argument))]
-    base_url: String,
+    #[knuffel(child, unwrap(argument, str))]
+    base_url: Url,
     #[knuffel(child, unwrap(argument))]
     pass_param: String,
+    /// If set, requests need this as their `token` query parameter, for calendar clients that
+    /// can't send an `Authorization` header. It isn't passed on to `base_url`.
+    #[knuffel(child, unwrap(argument, str))]
+    access_token: Option<Secret>,
+    /// Sent as the `User-Agent` when fetching the calendar, for providers that only talk to clients
+    /// they know. Defaults to `reasonable-excuse/{version}`.
     #[knuffel(child, unwrap(argument))]
-    filter: String,
+    user_agent: Option<String>,
+    /// Further query parameters passed on to `base_url` if the request has them. Any others are
+    /// dropped.
+    #[knuffel(children(name = "forward-param"), unwrap(argument))]
+    forward_params: Vec<String>,
+    #[knuffel(children(name = "filter"))]
+    filters: Vec<FilterConfig>,
+    /// How long a fetched and filtered calendar is served from memory. 0 disables caching.
+    #[knuffel(child, unwrap(argument), default)]
+    cache_seconds: u64,
+    /// Drop events that started more than this many days ago.
+    #[knuffel(child, unwrap(argument))]
+    from_days: Option<i64>,
+    /// Drop events that start more than this many days from now.
+    #[knuffel(child, unwrap(argument))]
+    to_days: Option<i64>,
+    /// Remove attendees, organizers and Microsoft-specific properties from events, which can
+    /// include other people's email addresses.
+    #[knuffel(child, unwrap(argument), default)]
+    strip_privacy: bool,
+}
+
+/// Events whose summary matches `pattern` are dropped, or, with a `replacement`, have the matches
+/// in their summary replaced by it. The replacement can refer to capture groups, e.g. `$1`. Both
+/// work on the summary as it's shown, i.e. with iCalendar escapes like `\,` resolved.
+#[derive(knuffel::Decode, Debug)]
+pub struct FilterConfig {
+    #[knuffel(argument)]
+    pattern: String,
+    #[knuffel(argument)]
+    replacement: Option<String>,
 }
 
-pub fn setup(config: Config, app: Router) -> miette::Result<Router> {
-    let config = Arc::new(config);
-    let client = Client::builder()
-        .user_agent(concat!("reasonable-excuse/", env!("CARGO_PKG_VERSION")))
-        .build()
-        .into_diagnostic()
-        .wrap_err("Failed to create reqwest Client")?;
+struct Filter {
+    regex: Regex,
+    replacement: Option<String>,
+}
 
-    let filter_regex = Regex::new(&config.filter)
-        .into_diagnostic()
-        .wrap_err("Failed to create filter regex")?;
+/// The compiled filters of a calendar. The version goes up on every reload, so calendars filtered
+/// with older filters can be told apart.
+struct Filters {
+    version: u64,
+    list: Vec<Filter>,
+}
 
-    Ok(app
-        .route(&config.route, axum::routing::get(get))
-        .layer(Extension(config))
-        .layer(Extension(filter_regex))
-        .layer(Extension(client)))
+/// A filtered calendar, along with when and in which version it was fetched from upstream, and
+/// which version of the filters was applied to it.
+struct CachedCalendar {
+    fetched: Instant,
+    etag: Option<HeaderValue>,
+    filters_version: u64,
+    body: String,
 }
 
-#[tracing::instrument(skip(client))]
+/// The last calendar fetched from each upstream URL, which differ by the `pass_param` value and
+/// any forwarded parameters.
+type Cache = RwLock<HashMap<String, CachedCalendar>>;
+
+/// How long calendars with an ETag are kept around for revalidating with upstream, even after
+/// `cache_seconds` has passed.
+const ETAG_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Everything needed to serve one calendar route.
+struct Calendar {
+    config: Config,
+    /// Compiled from `config.filters` at first, but replaced on reload.
+    filters: ArcSwap<Filters>,
+    /// Parsed from `config.user_agent`.
+    user_agent: Option<HeaderValue>,
+    cache: Cache,
+    client: Client,
+}
+
+/// Handle to all configured calendars, to update their filters at runtime.
+#[derive(Clone)]
+pub struct Calendars(Arc<Vec<Arc<Calendar>>>);
+
+impl Calendars {
+    /// Swaps in the filters from `configs` for every calendar that already exists. Calendars that
+    /// were added or removed, as well as any other changes, only take effect after a restart. If
+    /// any filter fails to compile, nothing is changed.
+    pub fn reload(&self, mut configs: Vec<Config>) -> miette::Result<usize> {
+        configs.retain(|config| config.enabled);
+        let mut updates = Vec::new();
+        for config in &configs {
+            match self.0.iter().find(|c| c.config.route == config.route) {
+                Some(calendar) => updates.push((calendar, compile_filters(&config.filters)?)),
+                None => tracing::warn!("New calendar {} requires a restart", config.route),
+            }
+        }
+        for calendar in self.0.iter() {
+            if !configs.iter().any(|c| c.route == calendar.config.route) {
+                tracing::warn!(
+                    "Removing calendar {} requires a restart",
+                    calendar.config.route
+                );
+            }
+        }
+
+        let count = updates.len();
+        for (calendar, list) in updates {
+            let version = calendar.filters.load().version + 1;
+            calendar.filters.store(Arc::new(Filters { version, list }));
+        }
+        Ok(count)
+    }
+}
+
+/// Checks that routes are unique and all filters compile, ignoring disabled calendars.
+pub fn validate(configs: &[Config]) -> miette::Result<()> {
+    let mut routes = HashSet::new();
+    for config in configs.iter().filter(|config| config.enabled) {
+        if !routes.insert(&config.route) {
+            miette::bail!(
+                "Calendar route {} is configured more than once",
+                config.route
+            );
+        }
+        compile_filters(&config.filters)?;
+        parse_user_agent(config.user_agent.as_deref())?;
+        if config.access_token.is_some()
+            && (config.pass_param == TOKEN_PARAM
+                || config.forward_params.iter().any(|p| p == TOKEN_PARAM))
+        {
+            miette::bail!(
+                "Calendar {} can't pass on the {TOKEN_PARAM} query parameter with an access-token",
+                config.route
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sets up one route per configured calendar.
+pub fn setup(
+    configs: Vec<Config>,
+    http_client: &http_client::Config,
+    mut app: Router,
+) -> miette::Result<(Router, Calendars)> {
+    let client = http_client::build(
+        http_client,
+        concat!("reasonable-excuse/", env!("CARGO_PKG_VERSION")),
+    )?;
+
+    validate(&configs)?;
+
+    let mut calendars = Vec::new();
+    for config in configs {
+        if !config.enabled {
+            tracing::info!("Calendar {} is disabled", config.route);
+            continue;
+        }
+        let calendar = setup_calendar(config, client.clone())?;
+        // Every calendar has its own state, so it is attached to just this route.
+        app = app.route(
+            &calendar.config.route,
+            axum::routing::get(get).layer(Extension(calendar.clone())),
+        );
+        calendars.push(calendar);
+    }
+
+    Ok((app, Calendars(Arc::new(calendars))))
+}
+
+fn setup_calendar(config: Config, client: Client) -> miette::Result<Arc<Calendar>> {
+    let list = compile_filters(&config.filters)?;
+    let user_agent = parse_user_agent(config.user_agent.as_deref())?;
+
+    if config.pass_param.is_empty() {
+        tracing::warn!(
+            "Calendar {} has an empty pass-param, so requests can't pass anything upstream",
+            config.route
+        );
+    }
+
+    Ok(Arc::new(Calendar {
+        config,
+        filters: ArcSwap::from_pointee(Filters { version: 0, list }),
+        user_agent,
+        cache: Cache::default(),
+        client,
+    }))
+}
+
+fn parse_user_agent(user_agent: Option<&str>) -> miette::Result<Option<HeaderValue>> {
+    user_agent
+        .map(|user_agent| {
+            HeaderValue::from_str(user_agent)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Invalid calendar user-agent {user_agent:?}"))
+        })
+        .transpose()
+}
+
+fn compile_filters(filters: &[FilterConfig]) -> miette::Result<Vec<Filter>> {
+    filters
+        .iter()
+        .map(|filter| {
+            let regex = Regex::new(&filter.pattern)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to create filter regex {:?}", filter.pattern))?;
+            Ok(Filter {
+                regex,
+                replacement: filter.replacement.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Query parameter the `access_token` is sent in.
+const TOKEN_PARAM: &str = "token";
+
+// The query parameters can contain the access token, so they are left out of the span.
+#[tracing::instrument(skip(params, calendar), fields(route = %calendar.config.route))]
 async fn get(
     Query(params): Query<HashMap<String, String>>,
     ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
-    Extension(config): Extension<Arc<Config>>,
-    Extension(filter): Extension<Regex>,
-    Extension(client): Extension<Client>,
-) -> Result<String, StatusCode> {
+    Extension(calendar): Extension<Arc<Calendar>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
     tracing::info!("Calendar request");
 
+    let Calendar {
+        config,
+        cache,
+        client,
+        ..
+    } = &*calendar;
+
+    if let Some(access_token) = &config.access_token {
+        let token = params.get(TOKEN_PARAM).map(String::as_str);
+        if !token.is_some_and(|token| access_token.matches(token)) {
+            tracing::warn!("Rejecting calendar request with missing or wrong token");
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "Invalid token"));
+        }
+    }
+
     let param = params.get(&config.pass_param).ok_or_else(|| {
         tracing::warn!("Bad calendar request, no {} query param", config.pass_param);
-        StatusCode::BAD_REQUEST
+        ApiError::bad_request(format!("Missing query parameter {}", config.pass_param))
     })?;
 
-    let url =
-        Url::parse_with_params(&config.base_url, &[(&config.pass_param, param)]).map_err(|e| {
-            tracing::error!("Failed to construct calendar request URL: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    let mut url = config.base_url.clone();
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair(&config.pass_param, param);
+        for name in &config.forward_params {
+            if let Some(value) = params.get(name) {
+                query.append_pair(name, value);
+            }
+        }
+    }
+    let cache_key = url.to_string();
 
-    let response = client.get(url).send().await.map_err(|e| {
-        tracing::error!("Failed to get base calendar: {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let if_none_match = headers.get(header::IF_NONE_MATCH);
 
-    let response = response.error_for_status().map_err(|e| {
-        tracing::error!("Failed to get base calendar: {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    // Calendars filtered before the last reload are neither served nor revalidated, since their
+    // body would still be filtered the old way.
+    let filters = calendar.filters.load_full();
+    let max_age = Duration::from_secs(config.cache_seconds);
+    let cached_etag = match cache
+        .read()
+        .await
+        .get(&cache_key)
+        .filter(|cached| cached.filters_version == filters.version)
+    {
+        Some(cached) if cached.fetched.elapsed() < max_age => {
+            tracing::debug!("Serving cached calendar");
+            let etag = filtered_etag(cached.etag.as_ref(), filters.version);
+            return Ok(respond(etag.as_ref(), &cached.body, if_none_match));
+        }
+        Some(cached) => cached.etag.clone(),
+        None => None,
+    };
 
-    let response = response.text().await.map_err(|e| {
+    let upstream_error = |e: reqwest::Error| {
         tracing::error!("Failed to get base calendar: {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
+        if e.is_timeout() {
+            ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "The upstream calendar did not respond in time",
+            )
+        } else {
+            ApiError::internal("Could not fetch the upstream calendar")
+        }
+    };
+
+    let mut request = client.get(url);
+    // Replaces the client's default.
+    if let Some(user_agent) = &calendar.user_agent {
+        request = request.header(header::USER_AGENT, user_agent);
+    }
+    if let Some(etag) = &cached_etag {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    let response = request.send().await.map_err(upstream_error)?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some(cached) = cache
+            .write()
+            .await
+            .get_mut(&cache_key)
+            .filter(|cached| cached.filters_version == filters.version)
+        {
+            tracing::debug!("Upstream calendar not modified, reusing cached one");
+            cached.fetched = Instant::now();
+            let etag = filtered_etag(cached.etag.as_ref(), filters.version);
+            return Ok(respond(etag.as_ref(), &cached.body, if_none_match));
+        }
+        // The cached calendar was evicted or replaced in the meantime, so there's nothing to reuse.
+        tracing::error!("Upstream calendar not modified, but it is no longer cached");
+        return Err(ApiError::internal("Could not fetch the upstream calendar"));
+    }
+
+    let response = response.error_for_status().map_err(upstream_error)?;
+    let etag = response.headers().get(header::ETAG).cloned();
+    let body = response.text().await.map_err(upstream_error)?;
+
+    let body = filter_events(&body, &calendar.config, &filters.list).map_err(|e| {
+        tracing::error!("Failed to filter base calendar: {e:?}");
+        ApiError::internal("Could not parse the upstream calendar")
     })?;
 
-    let response = filter.replace_all(&response, "");
+    let response = respond(
+        filtered_etag(etag.as_ref(), filters.version).as_ref(),
+        &body,
+        if_none_match,
+    );
+
+    if config.cache_seconds > 0 || etag.is_some() {
+        let mut cache = cache.write().await;
+        cache.retain(|_, cached| {
+            let max_age = if cached.etag.is_some() {
+                max_age.max(ETAG_MAX_AGE)
+            } else {
+                max_age
+            };
+            cached.fetched.elapsed() < max_age
+        });
+        cache.insert(
+            cache_key,
+            CachedCalendar {
+                fetched: Instant::now(),
+                etag,
+                filters_version: filters.version,
+                body,
+            },
+        );
+    }
+
+    Ok(response)
+}
+
+/// The ETag of a calendar filtered with the given version of the filters. It's derived from the
+/// upstream one so it still changes with upstream, but also changes on reload so clients don't
+/// keep a calendar filtered the old way. It's always weak, as filtering may change the bytes but
+/// not what they mean.
+fn filtered_etag(upstream: Option<&HeaderValue>, filters_version: u64) -> Option<HeaderValue> {
+    let upstream = upstream?.to_str().ok()?.trim();
+    let tag = upstream.trim_start_matches("W/").trim_matches('"');
+    HeaderValue::from_str(&format!("W/\"{tag}-{filters_version}\"")).ok()
+}
+
+/// Answers with the calendar, or with a 304 if the client already has this version of it.
+fn respond(
+    etag: Option<&HeaderValue>,
+    body: &str,
+    if_none_match: Option<&HeaderValue>,
+) -> Response {
+    let Some(etag) = etag else {
+        return body.to_string().into_response();
+    };
+
+    if if_none_match.is_some_and(|tags| etag_matches(etag, tags)) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag.clone())]).into_response();
+    }
+    ([(header::ETAG, etag.clone())], body.to_string()).into_response()
+}
+
+/// Whether an `If-None-Match` header matches `etag`, using the weak comparison the header calls
+/// for.
+fn etag_matches(etag: &HeaderValue, if_none_match: &HeaderValue) -> bool {
+    let (Ok(etag), Ok(if_none_match)) = (etag.to_str(), if_none_match.to_str()) else {
+        return false;
+    };
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag))
+}
+
+/// Applies the filters to every event's summary, in order, and drops events matched by a filter
+/// without a replacement or that start outside of the configured date range. With `strip_privacy`,
+/// properties identifying other people are removed from the remaining events. Everything else,
+/// including time zone definitions and calendar-level properties, is passed through untouched.
+fn filter_events(ics: &str, config: &Config, filters: &[Filter]) -> miette::Result<String> {
+    let mut nodes = ics::parse(ics)?;
+
+    let now = Utc::now();
+    let from = config.from_days.map(|days| now - TimeDelta::days(days));
+    let to = config.to_days.map(|days| now + TimeDelta::days(days));
+
+    for node in &mut nodes {
+        if let ics::Node::Component(vcalendar) = node {
+            vcalendar.retain_components("VEVENT", |event| {
+                if let Some(summary) = event.property_mut("SUMMARY") {
+                    let original = summary.text();
+                    let mut rewritten = original.clone();
+                    for filter in filters.iter() {
+                        match &filter.replacement {
+                            None if filter.regex.is_match(&rewritten) => return false,
+                            None => {}
+                            Some(replacement) => {
+                                rewritten = filter
+                                    .regex
+                                    .replace_all(&rewritten, replacement.as_str())
+                                    .into_owned();
+                            }
+                        }
+                    }
+                    if rewritten != original {
+                        summary.set_text(&rewritten);
+                    }
+                }
+
+                if config.strip_privacy {
+                    event.retain_properties(|p| {
+                        p.name != "ATTENDEE"
+                            && p.name != "ORGANIZER"
+                            && !p.name.starts_with("X-MICROSOFT-")
+                    });
+                }
+
+                is_in_range(event, from, to)
+            });
+        }
+    }
+
+    Ok(ics::write(&nodes))
+}
+
+/// Whether an event starts between `from` and `to`. Events we can't make sense of are kept.
+fn is_in_range(
+    event: &ics::Component,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> bool {
+    let Some(start) = event_start(event) else {
+        return true;
+    };
+    // Later instances of a recurring event may still fall into the range even if the first one is
+    // too old, so those are only dropped if they start too late.
+    let is_recurring = event.property("RRULE").is_some();
+    let too_early = !is_recurring && from.is_some_and(|from| start < from);
+    let too_late = to.is_some_and(|to| start > to);
+    !too_early && !too_late
+}
+
+/// Parses an event's DTSTART, which is either a date (all-day events) or a date and time, into
+/// UTC. Both are taken to be in the TZID if there is one. Times without a time zone, as well as
+/// TZIDs that aren't in the tz database, such as Windows time zone names, are treated as UTC.
+fn event_start(event: &ics::Component) -> Option<DateTime<Utc>> {
+    let dtstart = event.property("DTSTART")?;
+    if let Some(value) = dtstart.value.strip_suffix('Z') {
+        let start = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+        return Some(start.and_utc());
+    }
+
+    let value = &dtstart.value;
+    let start = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y%m%d")
+                .ok()
+                .map(|date| date.and_time(NaiveTime::MIN))
+        })?;
+    let tz = dtstart
+        .param("TZID")
+        .and_then(|tzid| tzid.parse::<Tz>().ok());
+    match tz {
+        // Times skipped by a DST change don't exist, so they are taken as UTC instead.
+        Some(tz) => tz
+            .from_local_datetime(&start)
+            .earliest()
+            .map(|start| start.with_timezone(&Utc))
+            .or(Some(start.and_utc())),
+        None => Some(start.and_utc()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filtered_etag_changes_with_filters_version() {
+        let upstream = HeaderValue::from_static("\"abc\"");
+        let before = filtered_etag(Some(&upstream), 0).unwrap();
+        let after = filtered_etag(Some(&upstream), 1).unwrap();
+        assert_eq!(before, "W/\"abc-0\"");
+        assert_eq!(after, "W/\"abc-1\"");
+        assert!(etag_matches(&before, &before));
+        assert!(!etag_matches(&after, &before));
+    }
+
+    #[test]
+    fn filtered_etag_is_weak_for_weak_upstream() {
+        let upstream = HeaderValue::from_static("W/\"abc\"");
+        assert_eq!(filtered_etag(Some(&upstream), 2).unwrap(), "W/\"abc-2\"");
+        assert_eq!(filtered_etag(None, 2), None);
+    }
+
+    fn test_config() -> Config {
+        Config {
+            enabled: true,
+            route: "/calendar".to_string(),
+            base_url: Url::parse("https://calendar.example.com/feed.ics").unwrap(),
+            pass_param: "id".to_string(),
+            access_token: None,
+            user_agent: None,
+            forward_params: Vec::new(),
+            filters: Vec::new(),
+            cache_seconds: 0,
+            from_days: None,
+            to_days: None,
+            strip_privacy: false,
+        }
+    }
+
+    fn test_filters(filters: &[(&str, Option<&str>)]) -> Vec<Filter> {
+        let configs: Vec<_> = filters
+            .iter()
+            .map(|(pattern, replacement)| FilterConfig {
+                pattern: pattern.to_string(),
+                replacement: replacement.map(str::to_string),
+            })
+            .collect();
+        compile_filters(&configs).unwrap()
+    }
+
+    const CALENDAR: &str = "BEGIN:VCALENDAR\r\n\
+        VERSION:2.0\r\n\
+        X-WR-CALNAME:Lectures\r\n\
+        BEGIN:VTIMEZONE\r\n\
+        TZID:Europe/Copenhagen\r\n\
+        END:VTIMEZONE\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:1\r\n\
+        SUMMARY:Cancelled: Lecture\r\n\
+        BEGIN:VALARM\r\n\
+        ACTION:DISPLAY\r\n\
+        END:VALARM\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:2\r\n\
+        SUMMARY:PRJ-123 Standup\r\n\
+        ATTENDEE;CN=\"Doe; John\":mailto:j@example.com\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+
+    #[test]
+    fn filter_events_drops_whole_events() {
+        let filters = test_filters(&[("^Cancelled:", None)]);
+        let filtered = filter_events(CALENDAR, &test_config(), &filters).unwrap();
+        assert!(!filtered.contains("UID:1"));
+        assert!(!filtered.contains("VALARM"));
+        assert_eq!(filtered.matches("BEGIN:VEVENT").count(), 1);
+        assert_eq!(filtered.matches("END:VEVENT").count(), 1);
+        assert!(filtered.contains("UID:2"));
+        // Everything outside of the dropped event is left alone.
+        assert!(filtered.contains("X-WR-CALNAME:Lectures\r\nBEGIN:VTIMEZONE\r\n"));
+        assert!(ics::parse(&filtered).is_ok());
+    }
+
+    #[test]
+    fn filter_events_passes_calendar_through_without_filters() {
+        let filtered = filter_events(CALENDAR, &test_config(), &[]).unwrap();
+        assert_eq!(filtered, CALENDAR);
+    }
+
+    #[test]
+    fn filter_events_escapes_replacements() {
+        let filters = test_filters(&[("PRJ-123 (.*)", Some("Alpha; $1, daily\\"))]);
+        let filtered = filter_events(CALENDAR, &test_config(), &filters).unwrap();
+        assert!(filtered.contains("SUMMARY:Alpha\\; Standup\\, daily\\\\\r\n"));
+    }
+
+    #[test]
+    fn filter_events_matches_unescaped_summaries() {
+        let calendar = CALENDAR.replace("PRJ-123 Standup", "Standup\\, daily");
+        let filters = test_filters(&[("^Standup, daily$", None)]);
+        let filtered = filter_events(&calendar, &test_config(), &filters).unwrap();
+        assert!(!filtered.contains("UID:2"));
+    }
+
+    #[test]
+    fn filter_events_strips_privacy() {
+        let config = Config {
+            strip_privacy: true,
+            ..test_config()
+        };
+        let filtered = filter_events(CALENDAR, &config, &[]).unwrap();
+        assert!(!filtered.contains("ATTENDEE"));
+        assert!(filtered.contains("SUMMARY:PRJ-123 Standup"));
+    }
+
+    /// A VEVENT with the given content lines.
+    fn event(lines: &[&str]) -> ics::Component {
+        let text = format!("BEGIN:VEVENT\r\n{}\r\nEND:VEVENT\r\n", lines.join("\r\n"));
+        match ics::parse(&text).unwrap().pop() {
+            Some(ics::Node::Component(event)) => event,
+            _ => unreachable!(),
+        }
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn event_start_handles_utc_and_floating_times() {
+        let start = |line| event_start(&event(&[line]));
+        assert_eq!(
+            start("DTSTART:20240310T120000Z"),
+            Some(utc("2024-03-10T12:00:00Z"))
+        );
+        assert_eq!(
+            start("DTSTART:20240310T120000"),
+            Some(utc("2024-03-10T12:00:00Z"))
+        );
+        assert_eq!(start("DTSTART:yesterday"), None);
+        assert_eq!(event_start(&event(&["SUMMARY:No start"])), None);
+    }
+
+    #[test]
+    fn event_start_resolves_tzid() {
+        let start = |line| event_start(&event(&[line]));
+        // Summer time in Berlin is UTC+2.
+        assert_eq!(
+            start("DTSTART;TZID=Europe/Berlin:20240701T120000"),
+            Some(utc("2024-07-01T10:00:00Z"))
+        );
+        assert_eq!(
+            start("DTSTART;TZID=\"America/New_York\":20240110T090000"),
+            Some(utc("2024-01-10T14:00:00Z"))
+        );
+        assert_eq!(
+            start("DTSTART;VALUE=DATE;TZID=Europe/Berlin:20240701"),
+            Some(utc("2024-06-30T22:00:00Z"))
+        );
+        assert_eq!(
+            start("DTSTART;TZID=W. Europe Standard Time:20240701T120000"),
+            Some(utc("2024-07-01T12:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn event_start_handles_all_day_events() {
+        assert_eq!(
+            event_start(&event(&["DTSTART;VALUE=DATE:20240310"])),
+            Some(utc("2024-03-10T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn is_in_range_compares_in_utc() {
+        let from = Some(utc("2024-07-01T10:00:00Z"));
+        let to = Some(utc("2024-07-31T00:00:00Z"));
+        let in_range = |lines: &[&str]| is_in_range(&event(lines), from, to);
+
+        assert!(in_range(&["DTSTART;TZID=Europe/Berlin:20240701T120000"]));
+        // Would be in range if the time was taken as UTC.
+        assert!(!in_range(&["DTSTART;TZID=Europe/Berlin:20240701T115959"]));
+        assert!(in_range(&["DTSTART:20240701T100000Z"]));
+        assert!(!in_range(&["DTSTART:20240701T095959Z"]));
+
+        assert!(in_range(&["DTSTART;VALUE=DATE:20240731"]));
+        assert!(!in_range(&["DTSTART;VALUE=DATE:20240801"]));
+        assert!(!in_range(&[
+            "DTSTART;TZID=America/New_York:20240730T210000"
+        ]));
+
+        assert!(in_range(&["SUMMARY:No start"]));
+    }
+
+    #[test]
+    fn is_in_range_keeps_recurring_events_that_started_earlier() {
+        let from = Some(utc("2024-07-01T00:00:00Z"));
+        let to = Some(utc("2024-07-31T00:00:00Z"));
+        let in_range = |lines: &[&str]| is_in_range(&event(lines), from, to);
 
-    Ok(response.to_string())
+        assert!(in_range(&["DTSTART:20230101T090000Z", "RRULE:FREQ=WEEKLY"]));
+        assert!(!in_range(&["DTSTART:20230101T090000Z"]));
+        assert!(!in_range(&[
+            "DTSTART:20240801T090000Z",
+            "RRULE:FREQ=WEEKLY"
+        ]));
+        assert!(in_range(&[
+            "DTSTART;VALUE=DATE:20240731",
+            "RRULE:FREQ=YEARLY"
+        ]));
+    }
 }
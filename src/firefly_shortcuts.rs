@@ -1,12 +1,41 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use axum::{extract::ConnectInfo, http::StatusCode, Extension, Json, Router};
+use arc_swap::ArcSwap;
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Path, Query},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json, Router,
+};
+use chrono::{
+    DateTime, Datelike, Days, FixedOffset, Local, Months, NaiveDate, NaiveTime, TimeZone,
+};
+use hmac::{Hmac, Mac};
 use miette::{Context, IntoDiagnostic};
 use reqwest::{Client, Method, RequestBuilder, Url};
+use sha3::Sha3_256;
+use tokio::sync::RwLock;
 
-#[derive(Clone, Debug, knuffel::Decode, serde::Serialize)]
+use crate::{
+    auth::{self, Auth, BasicAuthConfig, Secret},
+    error::ApiError,
+    http_client, request_id,
+};
+
+#[derive(Clone, Debug, knuffel::Decode)]
 struct Shortcut {
     shortcut_id: u64,
+    /// Fixed ID for the shortcut. Shortcuts without one are numbered automatically, so their IDs
+    /// change when shortcuts are added or removed before them.
+    #[knuffel(property(name = "id"))]
+    id: Option<u64>,
     #[knuffel(argument)]
     shortcut_name: String,
     #[knuffel(property(name = "icon"))]
@@ -14,190 +43,1986 @@ struct Shortcut {
 
     #[knuffel(child, unwrap(argument))]
     name: String,
+    /// Name of the Firefly instance to create the transaction in. Defaults to the default one.
+    #[knuffel(child, unwrap(argument))]
+    instance: Option<String>,
+    #[knuffel(child, unwrap(argument), default)]
+    transaction_type: TransactionType,
+    /// The account the money comes from, sent to Firefly as `source_name`. Which kind of account
+    /// that is depends on the `transaction_type`.
+    ///
+    /// Falls back to the configured `default-source`, except for deposits.
+    #[knuffel(child, unwrap(argument))]
+    source: Option<String>,
+    /// Firefly ID of the source account, instead of its name. Names aren't unique across account
+    /// types, IDs are.
+    #[knuffel(child, unwrap(argument))]
+    source_id: Option<String>,
+    /// The account the money goes to, sent to Firefly as `destination_name`.
     #[knuffel(child, unwrap(argument))]
-    source: String,
+    destination: Option<String>,
+    /// Firefly ID of the destination account, instead of its name.
     #[knuffel(child, unwrap(argument))]
-    destination: String,
+    destination_id: Option<String>,
     #[knuffel(child, unwrap(argument))]
     amount: Option<f32>,
+    /// For shortcuts without an `amount`: used when the request doesn't give one, and offered to
+    /// clients as the value to start from when asking for it.
+    #[knuffel(child, unwrap(argument))]
+    default_amount: Option<f32>,
+    /// For shortcuts without an `amount`: shown to clients when asking for it, e.g. "Total of the
+    /// receipt".
+    #[knuffel(child, unwrap(argument))]
+    amount_hint: Option<String>,
+    /// Amount in `foreign_currency_code`, in addition to `amount` in the account's currency.
+    #[knuffel(child, unwrap(argument))]
+    foreign_amount: Option<f32>,
+    #[knuffel(child, unwrap(argument))]
+    foreign_currency_code: Option<String>,
+    /// Falls back to the configured `default-budget`. Only withdrawals can have a budget.
     #[knuffel(child, unwrap(argument))]
     budget: Option<String>,
+    /// Falls back to the configured `default-category`.
     #[knuffel(child, unwrap(argument))]
     category: Option<String>,
+    /// Name of a piggy bank to link the transaction to.
+    #[knuffel(child, unwrap(argument))]
+    piggy_bank: Option<String>,
+    #[knuffel(children(name = "tag"), unwrap(argument))]
+    tags: Vec<String>,
+    /// Free-text notes attached to every split of the transaction.
+    #[knuffel(child, unwrap(argument))]
+    notes: Option<String>,
+    #[knuffel(children(name = "split"))]
+    splits: Vec<Split>,
+    /// Have Firefly reject the transaction if an identical one already exists. Firefly compares a
+    /// hash over all fields, so this only catches exact duplicates, e.g. from retried requests, but
+    /// also rejects legitimately repeated entries like a bill entered with the same amount every
+    /// month. Defaults to true and can be overridden per request.
+    #[knuffel(child, unwrap(argument), default = true)]
+    error_if_duplicate_hash: bool,
+    /// Let Firefly apply its rules to the new transaction. Defaults to true.
+    #[knuffel(child, unwrap(argument), default = true)]
+    apply_rules: bool,
+    /// Let Firefly fire its webhooks for the new transaction. Defaults to true.
+    #[knuffel(child, unwrap(argument), default = true)]
+    fire_webhooks: bool,
+    /// Only create the transaction for requests with `confirmed: true`. Others get a 409
+    /// describing the transaction, to be confirmed by sending the request again.
+    #[knuffel(child, unwrap(argument), default)]
+    requires_confirmation: bool,
+}
+
+/// The kind of transaction a shortcut creates. Firefly's source and destination follow the money
+/// for all of them, so a shortcut's `source` and `destination` are sent as they are:
+///
+/// - withdrawals go from an asset account to an expense account,
+/// - deposits go from a revenue account to an asset account,
+/// - transfers go from one asset account to another.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, knuffel::DecodeScalar)]
+enum TransactionType {
+    #[default]
+    Withdrawal,
+    Deposit,
+    Transfer,
+}
+
+impl TransactionType {
+    /// The name Firefly uses for it.
+    fn as_str(self) -> &'static str {
+        match self {
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Deposit => "deposit",
+            TransactionType::Transfer => "transfer",
+        }
+    }
+
+    /// Whether the source is one of our own asset accounts, which `default-source` is meant for.
+    fn has_asset_source(self) -> bool {
+        self != TransactionType::Deposit
+    }
+}
+
+impl Shortcut {
+    /// The name of the source account, falling back to `default-source` where that makes sense.
+    fn source<'a>(&'a self, config: &'a Config) -> Option<&'a String> {
+        let default_source = config
+            .default_source
+            .as_ref()
+            .filter(|_| self.transaction_type.has_asset_source());
+        self.source.as_ref().or(default_source)
+    }
+}
+
+/// A Firefly instance, with the PAT to use for it.
+#[derive(Clone, Debug, knuffel::Decode)]
+struct InstanceConfig {
+    #[knuffel(argument)]
+    name: String,
+    #[knuffel(child, unwrap(argument, str))]
+    firefly_url: Url,
+    #[knuffel(child, unwrap(argument))]
+    pat_file: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pat_env: Option<String>,
+}
+
+/// Name of the instance configured directly in the firefly_shortcuts block.
+const DEFAULT_INSTANCE: &str = "default";
+
+/// One part of a split transaction. Destination and category fall back to the values configured on
+/// the shortcut itself when not given.
+#[derive(Clone, Debug, knuffel::Decode)]
+struct Split {
+    #[knuffel(child, unwrap(argument))]
+    amount: f32,
+    #[knuffel(child, unwrap(argument))]
+    destination: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    category: Option<String>,
+}
+
+#[derive(knuffel::Decode, Debug)]
+pub struct Config {
+    /// When false, the module is left out entirely, along with the Firefly health check.
+    #[knuffel(child, unwrap(argument), default = true)]
+    enabled: bool,
+    #[knuffel(child, unwrap(argument))]
+    route: String,
+    /// The Firefly instance used unless a shortcut picks another one. It is called
+    /// `DEFAULT_INSTANCE` and can be left out if there are `instances`.
+    #[knuffel(child, unwrap(argument, str))]
+    firefly_url: Option<Url>,
+    #[knuffel(child, unwrap(argument))]
+    pat_file: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pat_env: Option<String>,
+    /// How often reading a `pat_file` is tried at startup before giving up, for files that only
+    /// show up after a while, e.g. on a network mount.
+    #[knuffel(child, unwrap(argument), default = 1)]
+    pat_file_attempts: u32,
+    #[knuffel(child, unwrap(argument), default = 5)]
+    pat_file_retry_seconds: u64,
+    /// Further Firefly instances for shortcuts to pick by name. Without a `firefly_url`, the first
+    /// of these is the default.
+    #[knuffel(children(name = "instance"))]
+    instances: Vec<InstanceConfig>,
+    /// If set, adding transactions requires this as a bearer token.
+    #[knuffel(child, unwrap(argument, str))]
+    auth_token: Option<Secret>,
+    /// Alternatively, require HTTP Basic auth with these credentials.
+    #[knuffel(child)]
+    basic_auth: Option<BasicAuthConfig>,
+    /// Secret of a Firefly webhook pointed at `{route}/webhook`. The endpoint only exists if set.
+    #[knuffel(child, unwrap(argument, str))]
+    webhook_secret: Option<Secret>,
+    /// Check at startup that the accounts of all shortcuts exist in Firefly and suit their
+    /// transaction type. Off by default since it requires Firefly to be reachable.
+    #[knuffel(child, unwrap(argument), default)]
+    validate_accounts: bool,
+    /// How often failed Firefly requests are retried, for connection errors and 5xx responses.
+    #[knuffel(child, unwrap(argument), default = 3)]
+    max_retries: u32,
+    #[knuffel(child, unwrap(argument), default = 300)]
+    budget_cache_seconds: u64,
+    #[knuffel(child, unwrap(argument), default = 300)]
+    category_cache_seconds: u64,
+    #[knuffel(child, unwrap(argument), default = 300)]
+    piggy_bank_cache_seconds: u64,
+    /// Sent as the `User-Agent` to Firefly. Defaults to `reasonable-excuse/{version}`.
+    #[knuffel(child, unwrap(argument))]
+    user_agent: Option<String>,
+    /// Used by withdrawal shortcuts that don't set their own `budget`.
+    #[knuffel(child, unwrap(argument))]
+    default_budget: Option<String>,
+    /// Used by shortcuts and splits that don't set their own `category`.
+    #[knuffel(child, unwrap(argument))]
+    default_category: Option<String>,
+    /// Used by shortcuts that don't set their own `source`, except deposits, whose source isn't one
+    /// of your own accounts.
+    #[knuffel(child, unwrap(argument))]
+    default_source: Option<String>,
+    /// Put before the description of every transaction created by a shortcut, separated by a
+    /// space, e.g. to find them with Firefly's search.
+    #[knuffel(child, unwrap(argument))]
+    description_prefix: Option<String>,
+    /// Put after the description of every transaction created by a shortcut, separated by a space.
+    #[knuffel(child, unwrap(argument))]
+    description_suffix: Option<String>,
+    #[knuffel(children(name = "shortcut"))]
+    shortcuts: Vec<Shortcut>,
+}
+
+/// Delay before the first retry of a failed Firefly request. Doubles with every further retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+
+/// A Firefly Personal Access Token.
+#[derive(Clone, Debug)]
+struct Pat(String);
+
+/// Everything needed to talk to one Firefly instance.
+struct Instance {
+    name: String,
+    firefly_url: Url,
+    pat: Pat,
+    /// Each instance has its own budgets and so on, so they can't share a cache.
+    cache: FireflyCache,
+}
+
+/// All configured Firefly instances, the default one first.
+#[derive(Clone)]
+struct Instances(Arc<Vec<Instance>>);
+
+impl Instances {
+    /// Looks up the instance called `name`, or the default one if not given.
+    fn get(&self, name: Option<&str>) -> Result<&Instance, ApiError> {
+        match name {
+            None => Ok(&self.0[0]),
+            Some(name) => self
+                .0
+                .iter()
+                .find(|instance| instance.name == name)
+                .ok_or_else(|| {
+                    ApiError::bad_request(format!("No Firefly instance named {name:?}"))
+                }),
+        }
+    }
+}
+
+/// Lets requests that aren't about a particular shortcut pick the Firefly instance.
+#[derive(Debug, serde::Deserialize)]
+struct InstanceQuery {
+    instance: Option<String>,
+}
+
+/// A value fetched from Firefly, along with the time it was fetched.
+type CacheEntry<T> = RwLock<Option<(Instant, Arc<T>)>>;
+
+/// Lists fetched from Firefly that are reused across requests.
+#[derive(Default)]
+struct FireflyCache {
+    budgets: CacheEntry<FireflyBudgetList>,
+    piggy_banks: CacheEntry<FireflyPiggyBankList>,
+    categories: CacheEntry<FireflyCategoryList>,
+}
+
+/// How many of each client's most recently created transactions can be undone.
+const UNDO_DEPTH: usize = 10;
+
+/// Instances and IDs of the transactions each client created most recently, oldest first.
+#[derive(Default)]
+struct RecentTransactions(RwLock<HashMap<IpAddr, VecDeque<(String, String)>>>);
+
+impl RecentTransactions {
+    async fn push(&self, client: IpAddr, created: &CreatedTransaction) {
+        self.push_entry(client, (created.instance.clone(), created.id.clone()))
+            .await;
+    }
+
+    async fn push_entry(&self, client: IpAddr, entry: (String, String)) {
+        let mut recent = self.0.write().await;
+        let ids = recent.entry(client).or_default();
+        if ids.len() >= UNDO_DEPTH {
+            ids.pop_front();
+        }
+        ids.push_back(entry);
+    }
+
+    async fn pop(&self, client: IpAddr) -> Option<(String, String)> {
+        self.0.write().await.get_mut(&client)?.pop_back()
+    }
+
+    /// Puts back a transaction taken with `pop` that couldn't be deleted after all, so undoing can
+    /// be retried.
+    async fn restore(&self, client: IpAddr, transaction: (String, String)) {
+        self.push_entry(client, transaction).await;
+    }
+}
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// How long the response to a request with an `Idempotency-Key` is remembered.
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(10 * 60);
+
+enum Idempotent {
+    InProgress,
+    Done(CreatedTransaction),
+}
+
+/// `Idempotency-Key`s of recent `add_transaction` requests, with when they were first seen. This
+/// uses a blocking mutex so that `IdempotencyGuard` can clean up when dropped.
+#[derive(Default)]
+struct IdempotencyKeys(std::sync::Mutex<HashMap<String, (Instant, Idempotent)>>);
+
+enum Begin {
+    /// The key is new, and the request should go ahead.
+    New(IdempotencyGuard),
+    /// An earlier request with the key created this transaction.
+    Replay(CreatedTransaction),
+}
+
+impl IdempotencyKeys {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Instant, Idempotent)>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn begin(self: &Arc<Self>, key: &str) -> Result<Begin, ApiError> {
+        let mut keys = self.lock();
+        keys.retain(|_, (seen, _)| seen.elapsed() < IDEMPOTENCY_TTL);
+        match keys.get(key) {
+            Some((_, Idempotent::InProgress)) => Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "A request with this Idempotency-Key is still in progress",
+            )),
+            Some((_, Idempotent::Done(created))) => Ok(Begin::Replay(created.clone())),
+            None => {
+                keys.insert(key.to_string(), (Instant::now(), Idempotent::InProgress));
+                Ok(Begin::New(IdempotencyGuard {
+                    keys: self.clone(),
+                    key: key.to_string(),
+                }))
+            }
+        }
+    }
+}
+
+/// Forgets its key again when dropped, unless a transaction was created. That way requests that
+/// failed or were aborted can be retried with the same key.
+struct IdempotencyGuard {
+    keys: Arc<IdempotencyKeys>,
+    key: String,
+}
+
+impl IdempotencyGuard {
+    fn created(self, created: &CreatedTransaction) {
+        if let Some((_, state)) = self.keys.lock().get_mut(&self.key) {
+            *state = Idempotent::Done(created.clone());
+        }
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        let mut keys = self.keys.lock();
+        if let Some((_, Idempotent::InProgress)) = keys.get(&self.key) {
+            keys.remove(&self.key);
+        }
+    }
+}
+
+/// Reads this module's config from the config file again, for `{route}/reload`.
+pub type ConfigLoader = Arc<dyn Fn() -> miette::Result<Config> + Send + Sync>;
+
+/// The shortcuts currently in use. They are kept separately from the rest of the config so they can
+/// be swapped out at runtime.
+#[derive(Clone)]
+pub struct Shortcuts {
+    current: Arc<ArcSwap<Vec<Shortcut>>>,
+    /// Instances can't be changed at runtime, so reloaded shortcuts have to stick to these.
+    instance_names: Arc<Vec<String>>,
+    /// The config requests take the defaults for shortcuts from, which is the one loaded at
+    /// startup.
+    config: Arc<Config>,
+}
+
+impl Shortcuts {
+    fn current(&self) -> Arc<Vec<Shortcut>> {
+        self.current.load_full()
+    }
+
+    /// Replaces the shortcuts with the ones from `config` and returns how many there are now. The
+    /// defaults for shortcuts can't change along with them, since requests keep using the ones from
+    /// startup, so reloads changing those are rejected. Any other changes in `config` only take
+    /// effect after a restart.
+    pub fn reload(&self, mut config: Config) -> miette::Result<usize> {
+        if !config.enabled {
+            miette::bail!("Disabling firefly_shortcuts requires a restart");
+        }
+        for ((name, old), (_, new)) in shortcut_defaults(&self.config)
+            .into_iter()
+            .zip(shortcut_defaults(&config))
+        {
+            if old != new {
+                miette::bail!("Changing {name} requires a restart");
+            }
+        }
+        validate_shortcuts(
+            &config.shortcuts,
+            self.config.default_source.as_ref(),
+            &self.instance_names,
+        )?;
+        assign_ids(&mut config.shortcuts)?;
+        let count = config.shortcuts.len();
+        self.current.store(Arc::new(config.shortcuts));
+        Ok(count)
+    }
+}
+
+/// The settings shortcuts fall back to, by their name in the config file.
+fn shortcut_defaults(config: &Config) -> [(&'static str, Option<&String>); 5] {
+    [
+        ("default-budget", config.default_budget.as_ref()),
+        ("default-category", config.default_category.as_ref()),
+        ("default-source", config.default_source.as_ref()),
+        ("description-prefix", config.description_prefix.as_ref()),
+        ("description-suffix", config.description_suffix.as_ref()),
+    ]
+}
+
+impl Config {
+    pub fn shortcut_count(&self) -> usize {
+        self.shortcuts.len()
+    }
+
+    /// All configured instances, the default one first.
+    fn instance_configs(&self) -> Vec<InstanceConfig> {
+        let default = self.firefly_url.as_ref().map(|firefly_url| InstanceConfig {
+            name: DEFAULT_INSTANCE.to_string(),
+            firefly_url: firefly_url.clone(),
+            pat_file: self.pat_file.clone(),
+            pat_env: self.pat_env.clone(),
+        });
+        default.into_iter().chain(self.instances.clone()).collect()
+    }
+}
+
+/// Checks everything that can be checked without reading the PAT or talking to Firefly.
+pub fn validate(config: &Config) -> miette::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    if config.firefly_url.is_none() && (config.pat_file.is_some() || config.pat_env.is_some()) {
+        miette::bail!("pat-file and pat-env are only used together with firefly-url");
+    }
+    let instances = config.instance_configs();
+    if instances.is_empty() {
+        miette::bail!("Either firefly-url or at least one instance must be set");
+    }
+    let mut instance_names = Vec::new();
+    for instance in &instances {
+        if instance_names.contains(&instance.name) {
+            miette::bail!(
+                "Firefly instance {:?} is configured more than once",
+                instance.name
+            );
+        }
+        if instance.pat_file.is_none() && instance.pat_env.is_none() {
+            miette::bail!(
+                "One of pat-file or pat-env must be set for Firefly instance {:?}",
+                instance.name
+            );
+        }
+        instance_names.push(instance.name.clone());
+    }
+    if config.pat_file_attempts == 0 {
+        miette::bail!("pat-file-attempts must be at least 1");
+    }
+    // All routes are nested under this one, which axum can't do at the root.
+    if !config.route.starts_with('/') || config.route == "/" {
+        miette::bail!(
+            "Firefly route {:?} must start with / and not be the root",
+            config.route
+        );
+    }
+    Auth::new(config.auth_token.as_ref(), config.basic_auth.as_ref())?;
+    if let Some(user_agent) = &config.user_agent {
+        HeaderValue::from_str(user_agent)
+            .into_diagnostic()
+            .with_context(|| format!("Invalid firefly user-agent {user_agent:?}"))?;
+    }
+    validate_shortcuts(
+        &config.shortcuts,
+        config.default_source.as_ref(),
+        &instance_names,
+    )?;
+    assign_ids(&mut config.shortcuts.clone())
+}
+
+/// Checks the shortcuts for combinations of settings that can't work.
+fn validate_shortcuts(
+    shortcuts: &[Shortcut],
+    default_source: Option<&String>,
+    instance_names: &[String],
+) -> miette::Result<()> {
+    for shortcut in shortcuts {
+        let name = &shortcut.shortcut_name;
+        if let Some(instance) = &shortcut.instance {
+            if !instance_names.contains(instance) {
+                miette::bail!("Shortcut {name:?} uses unknown Firefly instance {instance:?}");
+            }
+        }
+        let has_default_source =
+            default_source.is_some() && shortcut.transaction_type.has_asset_source();
+        match (&shortcut.source, &shortcut.source_id) {
+            (Some(_), Some(_)) => miette::bail!("Shortcut {name:?} has both source and source-id"),
+            (None, None) if !has_default_source => {
+                miette::bail!("Shortcut {name:?} has no source and there is no default-source")
+            }
+            _ => {}
+        }
+        if shortcut.budget.is_some() && shortcut.transaction_type != TransactionType::Withdrawal {
+            miette::bail!("Shortcut {name:?} can't have a budget, only withdrawals can");
+        }
+        // `default-source` is one of your own accounts, which deposits can't come from.
+        if shortcut.transaction_type == TransactionType::Deposit
+            && shortcut.source.is_some()
+            && shortcut.source.as_ref() == default_source
+        {
+            miette::bail!("Shortcut {name:?} is a deposit from default-source, use a transfer");
+        }
+        if shortcut.transaction_type == TransactionType::Transfer {
+            let source = shortcut
+                .source
+                .as_ref()
+                .or(default_source.filter(|_| shortcut.source_id.is_none()));
+            let same_name = source.is_some() && source == shortcut.destination.as_ref();
+            let same_id =
+                shortcut.source_id.is_some() && shortcut.source_id == shortcut.destination_id;
+            if same_name || same_id {
+                miette::bail!("Shortcut {name:?} transfers from an account to itself");
+            }
+        }
+        match (&shortcut.destination, &shortcut.destination_id) {
+            (Some(_), Some(_)) => {
+                miette::bail!("Shortcut {name:?} has both destination and destination-id")
+            }
+            (None, None) => miette::bail!("Shortcut {name:?} has no destination"),
+            _ => {}
+        }
+        if shortcut.foreign_currency_code.is_some() {
+            if shortcut.foreign_amount.is_none() {
+                miette::bail!(
+                    "Shortcut {name:?} has a foreign-currency-code but no foreign-amount"
+                );
+            }
+            if !shortcut.splits.is_empty() {
+                miette::bail!(
+                    "Shortcut {name:?} can't use a foreign currency together with splits"
+                );
+            }
+        } else if shortcut.foreign_amount.is_some() {
+            miette::bail!("Shortcut {name:?} has a foreign-amount but no foreign-currency-code");
+        }
+        if shortcut.default_amount.is_some()
+            && (shortcut.amount.is_some() || !shortcut.splits.is_empty())
+        {
+            miette::bail!(
+                "Shortcut {name:?} has a default-amount, but its amount is already fixed"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Gives every shortcut its explicit ID, and generates IDs for the rest from the ones not taken.
+fn assign_ids(shortcuts: &mut [Shortcut]) -> miette::Result<()> {
+    let mut taken = HashSet::new();
+    for shortcut in shortcuts.iter() {
+        if let Some(id) = shortcut.id {
+            if !taken.insert(id) {
+                miette::bail!("Shortcut ID {id} is used more than once");
+            }
+        }
+    }
+
+    let mut next_id = 0;
+    for shortcut in shortcuts {
+        shortcut.shortcut_id = match shortcut.id {
+            Some(id) => id,
+            None => {
+                while !taken.insert(next_id) {
+                    next_id += 1;
+                }
+                next_id
+            }
+        };
+    }
+    Ok(())
+}
+
+/// Everything needed to talk to Firefly, for use by other modules.
+#[derive(Clone)]
+pub struct Connection {
+    client: Client,
+    instances: Instances,
+}
+
+impl Connection {
+    /// Checks that every Firefly instance is reachable and accepts our PAT. The instances are all
+    /// checked at once, so this takes at most `timeout` no matter how many there are.
+    pub async fn ping(&self, timeout: Duration) -> miette::Result<()> {
+        let pings = self.instances.0.iter().map(|instance| async move {
+            firefly_req(&self.client, instance, Method::GET, "/v1/about")
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .into_diagnostic()
+                .with_context(|| format!("fetching about info of Firefly {:?}", instance.name))
+        });
+        tokio::time::timeout(timeout, futures_util::future::try_join_all(pings))
+            .await
+            .map_err(|_| miette::miette!("Firefly did not respond within {timeout:?}"))??;
+        Ok(())
+    }
+}
+
+pub async fn setup(
+    mut config: Config,
+    http_client: &http_client::Config,
+    load_config: ConfigLoader,
+    app: Router,
+) -> miette::Result<(Router, Option<Connection>, Option<Shortcuts>)> {
+    if !config.enabled {
+        tracing::info!("firefly_shortcuts module is disabled");
+        return Ok((app, None, None));
+    }
+    validate(&config)?;
+    let mut shortcuts = std::mem::take(&mut config.shortcuts);
+    assign_ids(&mut shortcuts)?;
+
+    let config = Arc::new(config);
+
+    let client = http_client::build(
+        http_client,
+        config
+            .user_agent
+            .as_deref()
+            .unwrap_or(concat!("reasonable-excuse/", env!("CARGO_PKG_VERSION"))),
+    )?;
+
+    let mut instances = Vec::new();
+    for instance in config.instance_configs() {
+        instances.push(Instance {
+            pat: read_pat(&instance, &config).await?,
+            name: instance.name,
+            firefly_url: instance.firefly_url,
+            cache: FireflyCache::default(),
+        });
+    }
+    let instances = Instances(Arc::new(instances));
+
+    if config.validate_accounts {
+        validate_accounts(&shortcuts, &config, &client, &instances)
+            .await
+            .context("validate shortcut accounts")?;
+    }
+
+    let shortcuts = Shortcuts {
+        current: Arc::new(ArcSwap::from_pointee(shortcuts)),
+        instance_names: Arc::new(instances.0.iter().map(|i| i.name.clone()).collect()),
+        config: config.clone(),
+    };
+    let recent = Arc::new(RecentTransactions::default());
+    let idempotency_keys = Arc::new(IdempotencyKeys::default());
+
+    let connection = Connection {
+        client: client.clone(),
+        instances: instances.clone(),
+    };
+
+    let auth = Auth::new(config.auth_token.as_ref(), config.basic_auth.as_ref())?;
+    let route = config.route.clone();
+    // Mounted under the configured route, so the extensions only apply to these routes.
+    let firefly = Router::new()
+        .route("/shortcuts", axum::routing::get(get_shortcuts))
+        .route(
+            "/add-transaction",
+            auth::protect(axum::routing::post(add_transaction), &auth),
+        )
+        .route(
+            "/add-transactions",
+            auth::protect(axum::routing::post(add_transactions), &auth),
+        )
+        .route(
+            "/transaction/:id",
+            auth::protect(axum::routing::delete(delete_transaction), &auth),
+        )
+        .route(
+            "/budgets",
+            auth::protect(axum::routing::get(get_budgets), &auth),
+        )
+        .route(
+            "/categories",
+            auth::protect(axum::routing::get(get_categories), &auth),
+        )
+        .route(
+            "/summary",
+            auth::protect(axum::routing::get(get_summary), &auth),
+        )
+        .route("/undo", auth::protect(axum::routing::post(undo), &auth))
+        .route("/reload", auth::protect(axum::routing::post(reload), &auth));
+    // Firefly signs its webhooks instead of authenticating like other clients.
+    let firefly = match &config.webhook_secret {
+        Some(secret) => firefly.route(
+            "/webhook",
+            axum::routing::post(webhook).layer(Extension(secret.clone())),
+        ),
+        None => firefly,
+    };
+    let firefly = firefly
+        .layer(Extension(config))
+        .layer(Extension(recent))
+        .layer(Extension(idempotency_keys))
+        .layer(Extension(shortcuts.clone()))
+        .layer(Extension(instances))
+        .layer(Extension(load_config))
+        .layer(Extension(client));
+    let app = app.nest(&route, firefly);
+
+    Ok((app, Some(connection), Some(shortcuts)))
+}
+
+/// Counts a failed call to the Firefly API for the metrics endpoint.
+fn count_firefly_failure() {
+    metrics::counter!("firefly_api_failures_total").increment(1);
+}
+
+/// Reads the PAT from `pat-file` or, if that isn't set, from the env var named by `pat-env`.
+async fn read_pat(instance: &InstanceConfig, config: &Config) -> miette::Result<Pat> {
+    let pat = match (&instance.pat_file, &instance.pat_env) {
+        (Some(pat_file), pat_env) => {
+            if let Some(pat_env) = pat_env {
+                tracing::warn!("Both pat-file and pat-env are set, ignoring env var {pat_env}");
+            }
+            read_pat_file(pat_file, config).await?
+        }
+        (None, Some(pat_env)) => std::env::var(pat_env)
+            .into_diagnostic()
+            .with_context(|| format!("read firefly PAT from env var: {pat_env}"))?,
+        (None, None) => miette::bail!("One of pat-file or pat-env must be set"),
+    };
+
+    let pat = pat.trim().to_string();
+    if pat.is_empty() {
+        miette::bail!("Firefly PAT of instance {:?} is empty", instance.name);
+    }
+    Ok(Pat(pat))
+}
+
+/// Reads `pat_file`, retrying up to `pat_file_attempts` times in total.
+async fn read_pat_file(pat_file: &str, config: &Config) -> miette::Result<String> {
+    let delay = Duration::from_secs(config.pat_file_retry_seconds);
+    let mut attempt = 1;
+    loop {
+        match tokio::fs::read_to_string(pat_file).await {
+            Ok(pat) => return Ok(pat),
+            Err(e) if attempt < config.pat_file_attempts => {
+                tracing::warn!(
+                    "Could not read firefly PAT from file {pat_file} (attempt {attempt} of {}), \
+                     retrying in {delay:?}: {e}",
+                    config.pat_file_attempts
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e)
+                    .into_diagnostic()
+                    .with_context(|| format!("read firefly PAT from file: {pat_file}"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FireflyAccount {
+    attributes: FireflyAccountAttribs,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FireflyAccountAttribs {
+    name: String,
+    #[serde(rename = "type")]
+    account_type: String,
+}
+
+impl FireflyAccountAttribs {
+    /// Whether this is one of your own accounts, rather than one money only comes from or goes to.
+    fn is_own(&self) -> bool {
+        matches!(
+            self.account_type.as_str(),
+            "asset" | "liability" | "liabilities"
+        )
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FireflyAccountList {
+    data: Vec<FireflyAccount>,
+}
+
+/// Makes sure the accounts of every shortcut exist in Firefly and have the right type for its
+/// transaction type.
+async fn validate_accounts(
+    shortcuts: &[Shortcut],
+    config: &Config,
+    client: &Client,
+    instances: &Instances,
+) -> miette::Result<()> {
+    let mut invalid = Vec::new();
+    for instance in instances.0.iter() {
+        let accounts = send_with_retries(
+            config,
+            firefly_req(client, instance, Method::GET, "/v1/accounts"),
+        )
+        .await
+        .and_then(|r| r.error_for_status())
+        .into_diagnostic()
+        .with_context(|| format!("fetching accounts of Firefly {:?}", instance.name))?
+        .json::<FireflyAccountList>()
+        .await
+        .into_diagnostic()
+        .context("parsing accounts")?;
+
+        // Different kinds of accounts may share a name, so a name counts as your own account if
+        // any of them is.
+        let mut own_accounts = HashMap::new();
+        for account in &accounts.data {
+            *own_accounts
+                .entry(account.attributes.name.as_str())
+                .or_default() |= account.attributes.is_own();
+        }
+
+        invalid.extend(
+            shortcuts
+                .iter()
+                .filter(|s| s.instance.as_deref().unwrap_or(&instances.0[0].name) == instance.name)
+                .filter_map(|s| {
+                    let problem = account_problem(s, config, &own_accounts)?;
+                    Some(format!("{:?} ({problem})", s.shortcut_name))
+                }),
+        );
+    }
+
+    if !invalid.is_empty() {
+        miette::bail!("Shortcuts have unusable accounts: {}", invalid.join(", "));
+    }
+    Ok(())
+}
+
+/// What's wrong with the accounts of `shortcut`, if anything, given the names of all accounts in
+/// Firefly and whether each is one of your own. Accounts given by ID can't be mixed up, so only
+/// names are checked. Expense and revenue accounts are created by Firefly on demand, so the other
+/// side of withdrawals and deposits only mustn't be one of your own accounts.
+fn account_problem(
+    shortcut: &Shortcut,
+    config: &Config,
+    own_accounts: &HashMap<&str, bool>,
+) -> Option<String> {
+    let source = shortcut
+        .source(config)
+        .filter(|_| shortcut.source_id.is_none());
+    let destination = shortcut
+        .destination
+        .as_ref()
+        .filter(|_| shortcut.destination_id.is_none());
+    let own = match shortcut.transaction_type {
+        TransactionType::Withdrawal => [source, None],
+        TransactionType::Deposit => [destination, None],
+        TransactionType::Transfer => [source, destination],
+    };
+
+    let kind = shortcut.transaction_type.as_str();
+    for name in own.into_iter().flatten() {
+        match own_accounts.get(name.as_str()) {
+            None => return Some(format!("account {name:?} does not exist")),
+            Some(false) => {
+                return Some(format!(
+                    "{kind} needs {name:?} to be an asset or liability account"
+                ))
+            }
+            Some(true) => {}
+        }
+    }
+    if shortcut.transaction_type == TransactionType::Deposit {
+        if let Some(source) = source.filter(|s| own_accounts.get(s.as_str()) == Some(&true)) {
+            return Some(format!(
+                "deposit from your own account {source:?}, which would have to be a transfer"
+            ));
+        }
+    }
+    None
+}
+
+/// What clients need to know about a shortcut to offer it. Accounts and everything else stay on
+/// the server.
+#[derive(Debug, serde::Serialize)]
+struct ShortcutSummary {
+    shortcut_id: u64,
+    shortcut_name: String,
+    shortcut_icon: String,
+    /// Whether the client has to ask for an amount, because the shortcut doesn't have one.
+    needs_amount: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount_hint: Option<String>,
+    /// Used if the client doesn't send an amount.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_amount: Option<f32>,
+    /// Whether requests have to be sent with `confirmed: true`.
+    requires_confirmation: bool,
+}
+
+impl From<&Shortcut> for ShortcutSummary {
+    fn from(shortcut: &Shortcut) -> Self {
+        ShortcutSummary {
+            shortcut_id: shortcut.shortcut_id,
+            shortcut_name: shortcut.shortcut_name.clone(),
+            shortcut_icon: shortcut.shortcut_icon.clone(),
+            // Splits bring their own amounts.
+            needs_amount: shortcut.amount.is_none() && shortcut.splits.is_empty(),
+            amount_hint: shortcut.amount_hint.clone(),
+            default_amount: shortcut.default_amount,
+            requires_confirmation: shortcut.requires_confirmation,
+        }
+    }
+}
+
+#[tracing::instrument(skip(shortcuts))]
+async fn get_shortcuts(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(shortcuts): Extension<Shortcuts>,
+) -> Result<Json<Vec<ShortcutSummary>>, StatusCode> {
+    tracing::info!("get_shortcuts request");
+
+    Ok(Json(
+        shortcuts
+            .current()
+            .iter()
+            .map(ShortcutSummary::from)
+            .collect(),
+    ))
+}
+
+/// Lists the names of all budgets in Firefly, to help with writing shortcuts.
+#[tracing::instrument(skip(config, client, instances))]
+async fn get_budgets(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(instances): Extension<Instances>,
+    Query(query): Query<InstanceQuery>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    tracing::info!("get_budgets request");
+
+    let instance = instances.get(query.instance.as_deref())?;
+    let budgets = fetch_budgets(&config, &client, instance)
+        .await
+        .map_err(|e| {
+            tracing::error!("Could not fetch budgets: {e:?}");
+            count_firefly_failure();
+            ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "Could not fetch budgets from Firefly",
+            )
+        })?;
+    Ok(Json(
+        budgets
+            .data
+            .iter()
+            .map(|b| b.attributes.name.clone())
+            .collect(),
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SummaryQuery {
+    /// Name of the only budget to summarize. All budgets if not given.
+    budget: Option<String>,
+    instance: Option<String>,
+}
+
+/// Spending within one budget limit of the current month.
+#[derive(Debug, serde::Serialize)]
+struct BudgetSummary {
+    budget: String,
+    currency_code: String,
+    limit: f64,
+    spent: f64,
+    remaining: f64,
+}
+
+/// Shows how much has been spent this month in each budget, and how much of its limit is left.
+/// Budgets without a limit for this month are left out.
+#[tracing::instrument(skip(config, client, instances))]
+async fn get_summary(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(instances): Extension<Instances>,
+    Query(query): Query<SummaryQuery>,
+) -> Result<Json<Vec<BudgetSummary>>, ApiError> {
+    tracing::info!("get_summary request");
+
+    let instance = instances.get(query.instance.as_deref())?;
+
+    // Looked up in the cached budget list directly rather than with `resolve_budget`, so that an
+    // unknown name can be told apart from Firefly being unreachable.
+    let all_budgets = fetch_budgets(&config, &client, instance)
+        .await
+        .map_err(|e| {
+            tracing::error!("Could not fetch budgets: {e:?}");
+            count_firefly_failure();
+            ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "Could not fetch budgets from Firefly",
+            )
+        })?;
+    let budgets = all_budgets
+        .data
+        .iter()
+        .filter(|b| {
+            query
+                .budget
+                .as_ref()
+                .is_none_or(|name| &b.attributes.name == name)
+        })
+        .map(|b| (b.id.clone(), b.attributes.name.clone()))
+        .collect::<Vec<_>>();
+    if let Some(name) = &query.budget {
+        if budgets.is_empty() {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("No budget named {name:?}"),
+            ));
+        }
+    }
+
+    let today = Local::now().date_naive();
+    let start = today.with_day(1).expect("every month has a first day");
+    let end = start + Months::new(1) - Days::new(1);
+
+    let mut summary = Vec::new();
+    for (id, name) in budgets {
+        let limits = fetch_budget_limits(&id, start, end, &config, &client, instance)
+            .await
+            .map_err(|e| {
+                tracing::error!("Could not fetch limits of budget {name:?}: {e:?}");
+                count_firefly_failure();
+                ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "Could not fetch budget limits from Firefly",
+                )
+            })?;
+        for limit in limits.data {
+            let attributes = limit.attributes;
+            let amount = attributes.amount.parse::<f64>().unwrap_or_default();
+            // Firefly reports spending as a negative amount.
+            let spent = attributes
+                .spent
+                .and_then(|spent| spent.parse::<f64>().ok())
+                .unwrap_or_default()
+                .abs();
+            summary.push(BudgetSummary {
+                budget: name.clone(),
+                currency_code: attributes.currency_code,
+                limit: amount,
+                spent,
+                remaining: amount - spent,
+            });
+        }
+    }
+
+    Ok(Json(summary))
+}
+
+/// Lists the names of all categories in Firefly, to help with writing shortcuts.
+#[tracing::instrument(skip(config, client, instances))]
+async fn get_categories(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(instances): Extension<Instances>,
+    Query(query): Query<InstanceQuery>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    tracing::info!("get_categories request");
+
+    let instance = instances.get(query.instance.as_deref())?;
+    let categories = fetch_categories(&config, &client, instance)
+        .await
+        .map_err(|e| {
+            tracing::error!("Could not fetch categories: {e:?}");
+            count_firefly_failure();
+            ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "Could not fetch categories from Firefly",
+            )
+        })?;
+    Ok(Json(
+        categories
+            .data
+            .iter()
+            .map(|c| c.attributes.name.clone())
+            .collect(),
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AddTransactionRequest {
+    shortcut_id: Option<u64>,
+    /// Alternative to `shortcut_id`, matched case-insensitively. Ignored if `shortcut_id` is set.
+    shortcut_name: Option<String>,
+    amount_override: Option<f32>,
+    /// Use this percentage of `total` as the amount. Both have to be given, and they can't be
+    /// combined with `amount_override`.
+    amount_percent: Option<f32>,
+    total: Option<f32>,
+    /// Replaces the shortcut's `foreign_amount`. Only valid for shortcuts with a foreign currency.
+    foreign_amount_override: Option<f32>,
+    /// Replaces the shortcut's `notes`.
+    notes_override: Option<String>,
+    /// Replaces the shortcut's `error_if_duplicate_hash`.
+    error_if_duplicate_hash: Option<bool>,
+    /// Date of the transaction, either RFC 3339 or `YYYY-MM-DD`. Defaults to now.
+    date: Option<String>,
+    /// Values for `{name}` placeholders in the shortcut's description, destination and category.
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    /// Tags added to the ones configured for the shortcut.
+    #[serde(default)]
+    extra_tags: Vec<String>,
+    /// Only build the Firefly request and return it, without actually creating a transaction.
+    #[serde(default)]
+    dry_run: bool,
+    /// Required for shortcuts with `requires_confirmation`. Not needed for dry runs.
+    #[serde(default)]
+    confirmed: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip_all, fields(%client_addr, ?req))]
+async fn add_transaction(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(shortcuts): Extension<Shortcuts>,
+    Extension(client): Extension<Client>,
+    Extension(instances): Extension<Instances>,
+    Extension(recent): Extension<Arc<RecentTransactions>>,
+    Extension(idempotency_keys): Extension<Arc<IdempotencyKeys>>,
+    headers: HeaderMap,
+    Json(req): Json<AddTransactionRequest>,
+) -> Result<Response, ApiError> {
+    tracing::info!("add_transaction request");
+
+    // Retries of a request that already went through get the original response instead of
+    // creating the transaction again. Dry runs don't create anything, so they don't need this.
+    let idempotency_key = headers
+        .get(&IDEMPOTENCY_KEY)
+        .and_then(|key| key.to_str().ok())
+        .filter(|_| !req.dry_run);
+    let mut idempotency_guard = None;
+    if let Some(key) = idempotency_key {
+        match idempotency_keys.begin(key)? {
+            Begin::New(guard) => idempotency_guard = Some(guard),
+            Begin::Replay(created) => {
+                tracing::info!("Replaying response for idempotency key {key}");
+                return Ok(Json(created).into_response());
+            }
+        }
+    }
+
+    let shortcuts = shortcuts.current();
+    match create_transaction(&shortcuts, &config, &client, &instances, &req).await? {
+        Outcome::DryRun(firefly_request) => {
+            let request_json = serde_json::to_string_pretty(&firefly_request).map_err(|e| {
+                tracing::error!("Failed to serialize store transaction request: {e:?}");
+                ApiError::internal("Failed to serialize the transaction")
+            })?;
+            Ok(([(header::CONTENT_TYPE, "application/json")], request_json).into_response())
+        }
+        Outcome::NeedsConfirmation(pending) => {
+            Ok((StatusCode::CONFLICT, Json(pending)).into_response())
+        }
+        Outcome::Created(created) => {
+            if let Some(guard) = idempotency_guard {
+                guard.created(&created);
+            }
+            recent.push(client_addr.ip(), &created).await;
+            Ok(Json(created).into_response())
+        }
+    }
+}
+
+/// The result of one item of `add_transactions`.
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+enum BulkResult {
+    Ok(Outcome),
+    Err { error: String },
+}
+
+/// Like `add_transaction`, but for several transactions at once. Each one is handled on its own,
+/// so a failing item doesn't affect the others.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(config, shortcuts, client, instances, recent, reqs))]
+async fn add_transactions(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(shortcuts): Extension<Shortcuts>,
+    Extension(client): Extension<Client>,
+    Extension(instances): Extension<Instances>,
+    Extension(recent): Extension<Arc<RecentTransactions>>,
+    Json(reqs): Json<Vec<AddTransactionRequest>>,
+) -> Json<Vec<BulkResult>> {
+    tracing::info!("add_transactions request with {} items", reqs.len());
+
+    // Budgets and categories are looked up through each instance's cache, so they are only fetched
+    // once for the whole batch.
+    let shortcuts = shortcuts.current();
+    let mut results = Vec::with_capacity(reqs.len());
+    for req in &reqs {
+        let result = match create_transaction(&shortcuts, &config, &client, &instances, req).await {
+            Ok(outcome) => {
+                if let Outcome::Created(created) = &outcome {
+                    recent.push(client_addr.ip(), created).await;
+                }
+                BulkResult::Ok(outcome)
+            }
+            Err(e) => BulkResult::Err { error: e.message },
+        };
+        results.push(result);
+    }
+
+    Json(results)
+}
+
+#[tracing::instrument(skip(config, client, instances))]
+async fn delete_transaction(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(instances): Extension<Instances>,
+    Path(id): Path<String>,
+    Query(query): Query<InstanceQuery>,
+) -> Result<StatusCode, ApiError> {
+    tracing::info!("delete_transaction request");
+
+    let instance = instances.get(query.instance.as_deref())?;
+    delete_firefly_transaction(&config, &client, instance, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Receives a webhook from Firefly and logs the event, after checking its signature.
+#[tracing::instrument(skip(secret, headers, body))]
+async fn webhook(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(secret): Extension<Secret>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let signature = headers
+        .get("signature")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if !verify_webhook_signature(&secret, signature, &body) {
+        tracing::warn!("Rejecting Firefly webhook with missing or invalid signature");
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "Missing or invalid signature",
+        ));
+    }
+
+    let event = serde_json::from_slice::<FireflyWebhookMessage>(&body).map_err(|e| {
+        tracing::warn!("Could not parse Firefly webhook: {e}");
+        ApiError::bad_request("Could not parse the webhook message")
+    })?;
+    tracing::info!(
+        uuid = %event.uuid,
+        trigger = %event.trigger,
+        response = %event.response,
+        "Firefly webhook"
+    );
+    tracing::debug!("Firefly webhook content: {}", event.content);
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FireflyWebhookMessage {
+    uuid: String,
+    /// What happened, e.g. `STORE_TRANSACTION`.
+    trigger: String,
+    /// What `content` is, e.g. `TRANSACTIONS`.
+    response: String,
+    content: serde_json::Value,
+}
+
+/// Checks a Firefly webhook `Signature` header, which looks like `t=<timestamp>,v1=<signature>`.
+/// Firefly signs `<timestamp>.<body>` with HMAC-SHA3-256 keyed with the webhook's secret.
+fn verify_webhook_signature(secret: &Secret, header: &str, body: &[u8]) -> bool {
+    let mut timestamp = None;
+    let mut signature = None;
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = Some(value),
+            Some(("v1", value)) => signature = hex::decode(value).ok(),
+            _ => {}
+        }
+    }
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return false;
+    };
+
+    let mut mac =
+        Hmac::<Sha3_256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    // Compares in constant time.
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ReloadResponse {
+    shortcuts: usize,
+}
+
+/// Reads the shortcuts from the config file again and swaps them in, like a SIGHUP does, but
+/// without touching anything else. Returns how many shortcuts there are now.
+#[tracing::instrument(skip(shortcuts, load_config))]
+async fn reload(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(shortcuts): Extension<Shortcuts>,
+    Extension(load_config): Extension<ConfigLoader>,
+) -> Result<Json<ReloadResponse>, ApiError> {
+    tracing::info!("Reloading shortcuts on request");
+
+    let count = load_config()
+        .and_then(|config| shortcuts.reload(config))
+        .map_err(|e| {
+            tracing::error!("Failed to reload shortcuts: {e:?}");
+            ApiError::internal(format!(
+                "Failed to reload shortcuts, keeping the old ones: {e}"
+            ))
+        })?;
+    tracing::info!("Reloaded firefly_shortcuts: {count} shortcuts");
+    Ok(Json(ReloadResponse { shortcuts: count }))
+}
+
+/// Deletes the most recent transaction created by the same client, and returns its ID.
+#[tracing::instrument(skip(config, client, instances, recent))]
+async fn undo(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(instances): Extension<Instances>,
+    Extension(recent): Extension<Arc<RecentTransactions>>,
+) -> Result<String, ApiError> {
+    tracing::info!("undo request");
+
+    // Taken off right away so concurrent undos can't both pick the same transaction.
+    let Some((instance_name, id)) = recent.pop(client_addr.ip()).await else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "No recent transaction to undo",
+        ));
+    };
+    let instance = instances.get(Some(&instance_name))?;
+    if let Err(e) = delete_firefly_transaction(&config, &client, instance, &id).await {
+        recent.restore(client_addr.ip(), (instance_name, id)).await;
+        return Err(e);
+    }
+    Ok(id)
+}
+
+async fn delete_firefly_transaction(
+    config: &Config,
+    client: &Client,
+    instance: &Instance,
+    id: &str,
+) -> Result<(), ApiError> {
+    // The ID ends up in the Firefly URL, so it mustn't be able to point anywhere else.
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ApiError::bad_request(format!(
+            "Invalid transaction ID {id:?}"
+        )));
+    }
+
+    let endpoint = format!("/v1/transactions/{id}");
+    let response = send_with_retries(
+        config,
+        firefly_req(client, instance, Method::DELETE, &endpoint),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to send delete transaction request: {e:?}");
+        count_firefly_failure();
+        if e.is_timeout() {
+            ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "Firefly did not respond in time",
+            )
+        } else {
+            ApiError::new(StatusCode::BAD_GATEWAY, "Could not reach Firefly")
+        }
+    })?;
+
+    match response.status() {
+        status if status.is_success() => {
+            tracing::info!("Deleted transaction {id}");
+            Ok(())
+        }
+        StatusCode::NOT_FOUND => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No transaction with ID {id}"),
+        )),
+        status => {
+            tracing::error!("Got API error deleting transaction {id}: {status}");
+            count_firefly_failure();
+            Err(ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                format!(
+                    "Firefly refused to delete the transaction with status {}",
+                    status.as_u16()
+                ),
+            ))
+        }
+    }
+}
+
+/// Finds the shortcut `req` is for, by its ID or otherwise its name.
+fn find_shortcut<'a>(
+    shortcuts: &'a [Shortcut],
+    req: &AddTransactionRequest,
+) -> Result<&'a Shortcut, ApiError> {
+    if let Some(id) = req.shortcut_id {
+        return shortcuts
+            .iter()
+            .find(|s| s.shortcut_id == id)
+            .ok_or_else(|| {
+                tracing::error!("Invalid shortcut ID");
+                ApiError::bad_request(format!("No shortcut with ID {id}"))
+            });
+    }
+
+    let Some(requested) = &req.shortcut_name else {
+        return Err(ApiError::bad_request(
+            "One of shortcut_id or shortcut_name is required",
+        ));
+    };
+    let name = requested.to_lowercase();
+    let mut matches = shortcuts
+        .iter()
+        .filter(|s| s.shortcut_name.to_lowercase() == name);
+    match (matches.next(), matches.next()) {
+        (Some(shortcut), None) => Ok(shortcut),
+        (None, _) => {
+            tracing::error!("Invalid shortcut name");
+            Err(ApiError::bad_request(format!(
+                "No shortcut named {requested:?}"
+            )))
+        }
+        (Some(_), Some(_)) => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("Several shortcuts are named {requested:?}, use shortcut_id instead"),
+        )),
+    }
+}
+
+/// What became of a single transaction request.
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+enum Outcome {
+    /// The request that would have been sent to Firefly, for dry runs.
+    DryRun(FireflyStoreTransactionRequest),
+    NeedsConfirmation(PendingTransaction),
+    Created(CreatedTransaction),
+}
+
+/// What a shortcut with `requires_confirmation` would do, sent back instead of creating the
+/// transaction until the request is confirmed.
+#[derive(Debug, serde::Serialize)]
+struct PendingTransaction {
+    error: &'static str,
+    description: String,
+    transactions: Vec<PendingSplit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct PendingSplit {
+    amount: String,
+    /// The destination account's name, or its ID if the shortcut refers to it by ID.
+    destination: Option<String>,
+}
+
+impl PendingTransaction {
+    fn new(request: &FireflyStoreTransactionRequest) -> Self {
+        let description = request.group_title.clone().unwrap_or_else(|| {
+            request
+                .transactions
+                .first()
+                .map(|t| t.description.clone())
+                .unwrap_or_default()
+        });
+        PendingTransaction {
+            error: "This shortcut has to be confirmed, send the request again with confirmed: true",
+            description,
+            transactions: request
+                .transactions
+                .iter()
+                .map(|split| PendingSplit {
+                    amount: split.amount.clone(),
+                    destination: split
+                        .destination_name
+                        .clone()
+                        .or_else(|| split.destination_id.clone()),
+                })
+                .collect(),
+            request_id: request_id::current(),
+        }
+    }
+}
+
+/// Builds the transaction for `req` and, unless it is a dry run, creates it in Firefly.
+async fn create_transaction(
+    shortcuts: &[Shortcut],
+    config: &Config,
+    client: &Client,
+    instances: &Instances,
+    req: &AddTransactionRequest,
+) -> Result<Outcome, ApiError> {
+    let shortcut = find_shortcut(shortcuts, req)?;
+    let instance = instances.get(shortcut.instance.as_deref())?;
+
+    // Resolve budget name to budget ID, if any. Firefly only has budgets for withdrawals.
+    let budget = shortcut
+        .budget
+        .as_ref()
+        .or(config.default_budget.as_ref())
+        .filter(|_| shortcut.transaction_type == TransactionType::Withdrawal);
+    let budget_id = resolve_budget(budget, config, client, instance)
+        .await
+        .map_err(|e| e.into_api_error("budget"))?;
+
+    let piggy_bank_id = resolve_piggy_bank(shortcut.piggy_bank.as_ref(), config, client, instance)
+        .await
+        .map_err(|e| e.into_api_error("piggy bank"))?;
+
+    // Build and send the transaction to the Firefly server.
+    let mut firefly_request =
+        make_store_transaction_request(shortcut, config, req, budget_id.as_ref()).map_err(|e| {
+            tracing::error!("Could not make store transaction request: {e:?}");
+            // These are all caused by the request, so the client gets to see the reason.
+            ApiError::bad_request(e.to_string())
+        })?;
+
+    for split in &mut firefly_request.transactions {
+        split.piggy_bank_id.clone_from(&piggy_bank_id);
+    }
+
+    // Resolve category names to category IDs where Firefly already knows the category.
+    for split in &mut firefly_request.transactions {
+        let category_id = resolve_category(split.category_name.as_ref(), config, client, instance)
+            .await
+            .map_err(|e| {
+                tracing::error!("Could not resolve category ID: {e:?}");
+                count_firefly_failure();
+                ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "Could not resolve the category in Firefly",
+                )
+            })?;
+        if category_id.is_some() {
+            split.category_id = category_id;
+            split.category_name = None;
+        }
+    }
+
+    if req.dry_run {
+        tracing::info!("Dry run, not sending transaction to Firefly");
+        return Ok(Outcome::DryRun(firefly_request));
+    }
+
+    if shortcut.requires_confirmation && !req.confirmed {
+        tracing::info!("Shortcut requires confirmation, not sending transaction to Firefly");
+        return Ok(Outcome::NeedsConfirmation(PendingTransaction::new(
+            &firefly_request,
+        )));
+    }
+
+    let response = send_with_retries(
+        config,
+        firefly_req(client, instance, Method::POST, "/v1/transactions").json(&firefly_request),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to send store transaction request: {e:?}");
+        count_firefly_failure();
+        if e.is_timeout() {
+            ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "Firefly did not respond in time",
+            )
+        } else {
+            ApiError::new(StatusCode::BAD_GATEWAY, "Could not reach Firefly")
+        }
+    })?;
+
+    let status_error = response.error_for_status_ref().err();
+
+    let response_text = response.text().await.map_err(|e| {
+        tracing::error!("Failed to read response text: {e:?}");
+        count_firefly_failure();
+        ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            "Could not read the response from Firefly",
+        )
+    })?;
+
+    if let Some(e) = status_error {
+        tracing::error!("Got API error: {e:?}, response: {response_text}");
+        count_firefly_failure();
+        return Err(ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            format!(
+                "Firefly rejected the transaction with status {}",
+                e.status().map_or(0, |s| s.as_u16())
+            ),
+        ));
+    }
+    tracing::trace!("Store transaction response: {response_text}");
+
+    let response = serde_json::from_str::<FireflyStoreTransactionResponse>(&response_text)
+        .map_err(|e| {
+            tracing::error!("Failed to parse store transaction response: {e:?}");
+            count_firefly_failure();
+            ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "Could not parse the response from Firefly",
+            )
+        })?;
+
+    let attributes = response.data.attributes;
+    let description = attributes.group_title.unwrap_or_else(|| {
+        attributes
+            .transactions
+            .into_iter()
+            .next()
+            .map(|t| t.description)
+            .unwrap_or_default()
+    });
+
+    Ok(Outcome::Created(CreatedTransaction {
+        id: response.data.id,
+        description,
+        instance: instance.name.clone(),
+    }))
+}
+
+/// What `add_transaction` reports back about the transaction it created.
+#[derive(Clone, Debug, serde::Serialize)]
+struct CreatedTransaction {
+    id: String,
+    description: String,
+    /// The Firefly instance the transaction was created in.
+    instance: String,
 }
 
-#[derive(knuffel::Decode, Debug)]
-pub struct Config {
-    #[knuffel(child, unwrap(argument))]
-    route: String,
-    #[knuffel(child, unwrap(argument, str))]
-    firefly_url: Url,
-    #[knuffel(child, unwrap(argument))]
-    pat_file: String,
-    #[knuffel(children(name = "shortcut"))]
-    shortcuts: Vec<Shortcut>,
+#[derive(Debug, serde::Deserialize)]
+struct FireflyStoreTransactionResponse {
+    data: FireflyTransactionGroup,
 }
 
-/// A Firefly Personal Access Token.
-#[derive(Clone, Debug)]
-struct Pat(String);
+#[derive(Debug, serde::Deserialize)]
+struct FireflyTransactionGroup {
+    id: String,
+    attributes: FireflyTransactionGroupAttribs,
+}
 
-pub fn setup(mut config: Config, app: Router) -> miette::Result<Router> {
-    // Generate IDs for all of the shortcuts.
-    for (i, shortcut) in config.shortcuts.iter_mut().enumerate() {
-        shortcut.shortcut_id = i as u64;
-    }
+#[derive(Debug, serde::Deserialize)]
+struct FireflyTransactionGroupAttribs {
+    group_title: Option<String>,
+    transactions: Vec<FireflyTransactionAttribs>,
+}
 
-    let config = Arc::new(config);
+#[derive(Debug, serde::Deserialize)]
+struct FireflyTransactionAttribs {
+    description: String,
+}
 
-    let client = Client::builder()
-        .user_agent(concat!("reasonable-excuse/", env!("CARGO_PKG_VERSION")))
-        .build()
-        .into_diagnostic()
-        .context("create reqwest Client")?;
+#[derive(Debug, serde::Deserialize)]
+struct FireflyBudget {
+    id: String,
+    attributes: FireflyBudgetAttribs,
+}
 
-    let pat = std::fs::read_to_string(&config.pat_file)
-        .into_diagnostic()
-        .with_context(|| format!("read firefly PAT from file: {}", config.pat_file))?;
-    let pat = pat.trim_end().to_string();
-    let pat = Arc::new(Pat(pat));
+#[derive(Debug, serde::Deserialize)]
+struct FireflyBudgetAttribs {
+    name: String,
+}
 
-    let base = &config.route;
-    Ok(app
-        .route(
-            &format!("{base}/shortcuts"),
-            axum::routing::get(get_shortcuts),
-        )
-        .route(
-            &format!("{base}/add-transaction"),
-            axum::routing::post(add_transaction),
+#[derive(Debug, serde::Deserialize)]
+struct FireflyBudgetList {
+    data: Vec<FireflyBudget>,
+}
+
+/// Returns all budgets, from the cache if possible.
+async fn fetch_budgets(
+    config: &Config,
+    client: &Client,
+    instance: &Instance,
+) -> miette::Result<Arc<FireflyBudgetList>> {
+    let max_age = Duration::from_secs(config.budget_cache_seconds);
+    get_cached(&instance.cache.budgets, max_age, async {
+        send_with_retries(
+            config,
+            firefly_req(client, instance, Method::GET, "/v1/budgets"),
         )
-        .layer(Extension(config))
-        .layer(Extension(pat))
-        .layer(Extension(client)))
+        .await
+        .and_then(|r| r.error_for_status())
+        .into_diagnostic()
+        .context("fetching budgets")?
+        .json::<FireflyBudgetList>()
+        .await
+        .into_diagnostic()
+        .context("parsing budgets")
+    })
+    .await
 }
 
-#[tracing::instrument(skip(config))]
-async fn get_shortcuts(
-    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
-    Extension(config): Extension<Arc<Config>>,
-) -> Result<Json<Vec<Shortcut>>, StatusCode> {
-    tracing::info!("get_shortcuts request");
+#[derive(Debug, serde::Deserialize)]
+struct FireflyBudgetLimit {
+    attributes: FireflyBudgetLimitAttribs,
+}
 
-    Ok(Json(config.shortcuts.clone()))
+#[derive(Debug, serde::Deserialize)]
+struct FireflyBudgetLimitAttribs {
+    amount: String,
+    spent: Option<String>,
+    currency_code: String,
 }
 
 #[derive(Debug, serde::Deserialize)]
-struct AddTransactionRequest {
-    shortcut_id: u64,
-    amount_override: Option<f32>,
+struct FireflyBudgetLimitList {
+    data: Vec<FireflyBudgetLimit>,
 }
 
-#[tracing::instrument(skip(config, client, pat))]
-async fn add_transaction(
-    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
-    Extension(config): Extension<Arc<Config>>,
-    Extension(client): Extension<Client>,
-    Extension(pat): Extension<Arc<Pat>>,
-    Json(req): Json<AddTransactionRequest>,
-) -> Result<String, StatusCode> {
-    tracing::info!("add_transaction request");
+/// Returns the limits of budget `id` overlapping the days from `start` to `end`. Not cached, since
+/// the amount spent changes with every transaction.
+async fn fetch_budget_limits(
+    id: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+    config: &Config,
+    client: &Client,
+    instance: &Instance,
+) -> miette::Result<FireflyBudgetLimitList> {
+    let endpoint = format!("/v1/budgets/{id}/limits");
+    send_with_retries(
+        config,
+        firefly_req(client, instance, Method::GET, &endpoint).query(&[
+            ("start", start.format("%Y-%m-%d").to_string()),
+            ("end", end.format("%Y-%m-%d").to_string()),
+        ]),
+    )
+    .await
+    .and_then(|r| r.error_for_status())
+    .into_diagnostic()
+    .context("fetching budget limits")?
+    .json::<FireflyBudgetLimitList>()
+    .await
+    .into_diagnostic()
+    .context("parsing budget limits")
+}
 
-    // Find shortcut with the given ID.
-    let Some(shortcut) = config
-        .shortcuts
-        .iter()
-        .find(|s| s.shortcut_id == req.shortcut_id)
-    else {
-        tracing::error!("Invalid shortcut ID");
-        return Err(StatusCode::BAD_REQUEST);
+/// Why looking up a budget or piggy bank by name failed, which decides whether it's the config's or
+/// Firefly's fault.
+#[derive(Debug)]
+enum ResolveError {
+    /// Firefly doesn't have anything with this name.
+    NotFound(String),
+    /// Firefly couldn't be asked.
+    Firefly(miette::Report),
+}
+
+impl ResolveError {
+    /// `what` is the kind of thing that was looked up, e.g. "budget".
+    fn into_api_error(self, what: &str) -> ApiError {
+        match self {
+            ResolveError::NotFound(name) => {
+                tracing::warn!("The {what} {name:?} does not exist in Firefly");
+                ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("There is no {what} named {name:?} in Firefly"),
+                )
+            }
+            ResolveError::Firefly(e) => {
+                tracing::error!("Could not resolve {what} ID: {e:?}");
+                count_firefly_failure();
+                ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    format!("Could not resolve the {what} in Firefly"),
+                )
+            }
+        }
+    }
+}
+
+async fn resolve_budget(
+    budget: Option<&String>,
+    config: &Config,
+    client: &Client,
+    instance: &Instance,
+) -> Result<Option<String>, ResolveError> {
+    let Some(budget_name) = budget else {
+        return Ok(None);
     };
 
-    // Resolve budget name to budget ID, if any.
-    let budget_id = resolve_budget(shortcut.budget.as_ref(), &config, &client, &pat)
+    let budgets = fetch_budgets(config, client, instance)
         .await
-        .map_err(|e| {
-            tracing::error!("Could not resolve budget ID: {e:?}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .map_err(ResolveError::Firefly)?;
+    for budget in &budgets.data {
+        if &budget.attributes.name == budget_name {
+            return Ok(Some(budget.id.clone()));
+        }
+    }
 
-    // Build and send the transaction to the Firefly server.
-    let firefly_request =
-        make_store_transaction_request(shortcut, req.amount_override, budget_id.as_ref()).map_err(
-            |e| {
-                tracing::error!("Could not make store transaction request: {e:?}");
-                StatusCode::BAD_REQUEST
-            },
-        )?;
-    let response = firefly_req(&config, &client, &pat, Method::POST, "/v1/transactions")
-        .json(&firefly_request)
-        .send()
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to send store transaction request: {e:?}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    Err(ResolveError::NotFound(budget_name.clone()))
+}
 
-    let status_error = response.error_for_status_ref().err();
+#[derive(Debug, serde::Deserialize)]
+struct FireflyPiggyBank {
+    id: String,
+    attributes: FireflyPiggyBankAttribs,
+}
 
-    let response_text = response.text().await.map_err(|e| {
-        tracing::error!("Failed to read response text: {e:?}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+#[derive(Debug, serde::Deserialize)]
+struct FireflyPiggyBankAttribs {
+    name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FireflyPiggyBankList {
+    data: Vec<FireflyPiggyBank>,
+}
+
+async fn resolve_piggy_bank(
+    piggy_bank: Option<&String>,
+    config: &Config,
+    client: &Client,
+    instance: &Instance,
+) -> Result<Option<String>, ResolveError> {
+    let Some(piggy_bank_name) = piggy_bank else {
+        return Ok(None);
+    };
+
+    let max_age = Duration::from_secs(config.piggy_bank_cache_seconds);
+    let piggy_banks = get_cached(&instance.cache.piggy_banks, max_age, async {
+        send_with_retries(
+            config,
+            firefly_req(client, instance, Method::GET, "/v1/piggy-banks"),
+        )
+        .await
+        .and_then(|r| r.error_for_status())
+        .into_diagnostic()
+        .context("fetching piggy banks")?
+        .json::<FireflyPiggyBankList>()
+        .await
+        .into_diagnostic()
+        .context("parsing piggy banks")
+    })
+    .await
+    .map_err(ResolveError::Firefly)?;
 
-    match status_error {
-        Some(e) => {
-            tracing::error!("Got API error: {e:?}, response: {response_text}");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+    for piggy_bank in &piggy_banks.data {
+        if &piggy_bank.attributes.name == piggy_bank_name {
+            return Ok(Some(piggy_bank.id.clone()));
         }
-        None => Ok(response_text),
     }
+
+    Err(ResolveError::NotFound(piggy_bank_name.clone()))
 }
 
 #[derive(Debug, serde::Deserialize)]
-struct FireflyBudget {
+struct FireflyCategory {
     id: String,
-    attributes: FireflyBudgetAttribs,
+    attributes: FireflyCategoryAttribs,
 }
 
 #[derive(Debug, serde::Deserialize)]
-struct FireflyBudgetAttribs {
+struct FireflyCategoryAttribs {
     name: String,
 }
 
 #[derive(Debug, serde::Deserialize)]
-struct FireflyBudgetList {
-    data: Vec<FireflyBudget>,
+struct FireflyCategoryList {
+    data: Vec<FireflyCategory>,
 }
 
-async fn resolve_budget(
-    budget: Option<&String>,
+/// Returns all categories, from the cache if possible.
+async fn fetch_categories(
     config: &Config,
     client: &Client,
-    pat: &Pat,
-) -> miette::Result<Option<String>> {
-    let Some(budget_name) = budget else {
-        return Ok(None);
-    };
-
-    let budgets = firefly_req(config, client, pat, Method::GET, "/v1/budgets")
-        .send()
+    instance: &Instance,
+) -> miette::Result<Arc<FireflyCategoryList>> {
+    let max_age = Duration::from_secs(config.category_cache_seconds);
+    get_cached(&instance.cache.categories, max_age, async {
+        send_with_retries(
+            config,
+            firefly_req(client, instance, Method::GET, "/v1/categories"),
+        )
         .await
         .and_then(|r| r.error_for_status())
         .into_diagnostic()
-        .context("fetching budgets")?
-        .json::<FireflyBudgetList>()
+        .context("fetching categories")?
+        .json::<FireflyCategoryList>()
         .await
         .into_diagnostic()
-        .context("parsing budgets")?;
+        .context("parsing categories")
+    })
+    .await
+}
 
-    for budget in budgets.data {
-        if &budget.attributes.name == budget_name {
-            return Ok(Some(budget.id));
+/// Looks up the ID of the category with the given name. Unlike budgets, unknown categories are not
+/// an error: Firefly creates them on the fly when given a `category_name`, so `None` is returned
+/// and the name should be sent as-is.
+async fn resolve_category(
+    category: Option<&String>,
+    config: &Config,
+    client: &Client,
+    instance: &Instance,
+) -> miette::Result<Option<String>> {
+    let Some(category_name) = category else {
+        return Ok(None);
+    };
+
+    let categories = fetch_categories(config, client, instance).await?;
+    let id = categories
+        .data
+        .iter()
+        .find(|c| &c.attributes.name == category_name)
+        .map(|c| c.id.clone());
+    if id.is_none() {
+        tracing::info!("Category {category_name} not found, Firefly will create it");
+    }
+    Ok(id)
+}
+
+/// Returns the cached value if it is younger than `max_age`, and otherwise replaces it with the
+/// result of `fetch`. Concurrent callers wait for a single fetch instead of each starting their own.
+async fn get_cached<T>(
+    entry: &CacheEntry<T>,
+    max_age: Duration,
+    fetch: impl Future<Output = miette::Result<T>>,
+) -> miette::Result<Arc<T>> {
+    if let Some((fetched, value)) = &*entry.read().await {
+        if fetched.elapsed() < max_age {
+            return Ok(value.clone());
+        }
+    }
+
+    let mut entry = entry.write().await;
+    // Someone else may have refreshed the entry while we were waiting for the write lock.
+    if let Some((fetched, value)) = &*entry {
+        if fetched.elapsed() < max_age {
+            return Ok(value.clone());
         }
     }
 
-    miette::bail!("Could not find budget with name {budget_name}");
+    let value = Arc::new(fetch.await?);
+    *entry = Some((Instant::now(), value.clone()));
+    Ok(value)
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -205,6 +2030,8 @@ struct FireflyStoreTransactionRequest {
     error_if_duplicate_hash: bool,
     apply_rules: bool,
     fire_webhooks: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_title: Option<String>,
     transactions: Vec<FireflyStoreTransactionSplit>,
 }
 
@@ -216,49 +2043,555 @@ struct FireflyStoreTransactionSplit {
     amount: String,
     description: String,
     budget_id: Option<String>,
+    category_id: Option<String>,
     category_name: Option<String>,
-    source_name: String,
-    destination_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_name: Option<String>,
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    foreign_amount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    foreign_currency_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    piggy_bank_id: Option<String>,
+}
+
+/// An account referenced by a shortcut, either by name or by its Firefly ID.
+#[derive(Clone, Copy)]
+enum AccountRef<'a> {
+    Name(&'a String),
+    Id(&'a String),
+}
+
+impl<'a> AccountRef<'a> {
+    fn new(name: Option<&'a String>, id: Option<&'a String>) -> Option<Self> {
+        id.map(AccountRef::Id).or(name.map(AccountRef::Name))
+    }
 }
 
+/// Builds the transaction for `shortcut`, using the defaults from `config` for anything it doesn't
+/// set itself.
 fn make_store_transaction_request(
     shortcut: &Shortcut,
-    amount_override: Option<f32>,
+    config: &Config,
+    req: &AddTransactionRequest,
     budget_id: Option<&String>,
 ) -> miette::Result<FireflyStoreTransactionRequest> {
-    let Some(amount) = amount_override.or(shortcut.amount) else {
-        miette::bail!("Must have at least one of shortcut.amount or amount_override");
+    let amount_override = match (req.amount_percent, req.total) {
+        (None, None) => req.amount_override,
+        (Some(percent), Some(total)) => {
+            if req.amount_override.is_some() {
+                miette::bail!("amount_override can't be combined with amount_percent and total");
+            }
+            // total * percent / 100, rounded to two decimal places.
+            Some((total * percent).round() / 100.0)
+        }
+        _ => miette::bail!("amount_percent and total must be given together"),
+    };
+    let now = Local::now();
+    let date = match &req.date {
+        Some(date) => parse_date(date)?,
+        None => now.fixed_offset(),
     };
-
     // 2018-09-17T12:46:47+01:00
-    let date = format!("{}", chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%:z"));
+    let date = format!("{}", date.format("%Y-%m-%dT%H:%M:%S%:z"));
 
-    Ok(FireflyStoreTransactionRequest {
-        error_if_duplicate_hash: true,
-        apply_rules: true,
-        fire_webhooks: true,
-        transactions: vec![FireflyStoreTransactionSplit {
-            transaction_type: "withdrawal".to_string(),
-            date: date,
+    let mut vars = req.vars.clone();
+    vars.entry("today".to_string())
+        .or_insert_with(|| now.format("%Y-%m-%d").to_string());
+
+    let mut description = fill_placeholders(&shortcut.name, &vars)?;
+    if let Some(prefix) = &config.description_prefix {
+        description = format!("{prefix} {description}");
+    }
+    if let Some(suffix) = &config.description_suffix {
+        description = format!("{description} {suffix}");
+    }
+
+    let mut tags = Vec::new();
+    for tag in shortcut.tags.iter().chain(&req.extra_tags) {
+        if !tags.contains(tag) {
+            tags.push(tag.clone());
+        }
+    }
+
+    let notes = req.notes_override.as_ref().or(shortcut.notes.as_ref());
+    let source = AccountRef::new(shortcut.source(config), shortcut.source_id.as_ref());
+    let Some(source) = source else {
+        miette::bail!("Shortcut has no source and there is no default-source");
+    };
+    let Some(destination) = AccountRef::new(
+        shortcut.destination.as_ref(),
+        shortcut.destination_id.as_ref(),
+    ) else {
+        miette::bail!("Shortcut has no destination");
+    };
+    let category = shortcut
+        .category
+        .as_ref()
+        .or(config.default_category.as_ref());
+
+    let make_split = |amount: f32, destination: AccountRef, category: Option<&String>| {
+        let (source_name, source_id) = match source {
+            AccountRef::Name(name) => (Some(name.clone()), None),
+            AccountRef::Id(id) => (None, Some(id.clone())),
+        };
+        let (destination_name, destination_id) = match destination {
+            AccountRef::Name(name) => (Some(fill_placeholders(name, &vars)?), None),
+            AccountRef::Id(id) => (None, Some(id.clone())),
+        };
+        Ok(FireflyStoreTransactionSplit {
+            transaction_type: shortcut.transaction_type.as_str().to_string(),
+            date: date.clone(),
             amount: amount.to_string(),
-            description: shortcut.name.clone(),
+            description: description.clone(),
             budget_id: budget_id.cloned(),
-            category_name: shortcut.category.clone(),
-            source_name: shortcut.source.clone(),
-            destination_name: shortcut.destination.clone(),
-        }],
+            category_id: None,
+            category_name: category.map(|c| fill_placeholders(c, &vars)).transpose()?,
+            source_id,
+            source_name,
+            destination_id,
+            destination_name,
+            tags: tags.clone(),
+            foreign_amount: None,
+            foreign_currency_code: None,
+            notes: notes.cloned(),
+            piggy_bank_id: None,
+        })
+    };
+
+    if req.foreign_amount_override.is_some() && shortcut.foreign_currency_code.is_none() {
+        miette::bail!("foreign_amount_override requires a shortcut with a foreign currency");
+    }
+
+    // Without explicit splits, the top-level amount/destination/category form a single implicit one.
+    let transactions = if shortcut.splits.is_empty() {
+        let amount = amount_override
+            .or(shortcut.amount)
+            .or(shortcut.default_amount);
+        let Some(amount) = amount else {
+            miette::bail!("Must have at least one of shortcut.amount or amount_override");
+        };
+        let mut split = make_split(amount, destination, category)?;
+        split.foreign_amount = req
+            .foreign_amount_override
+            .or(shortcut.foreign_amount)
+            .map(|amount| amount.to_string());
+        split.foreign_currency_code = shortcut.foreign_currency_code.clone();
+        vec![split]
+    } else {
+        if amount_override.is_some() {
+            miette::bail!("amount_override is not supported for shortcuts with splits");
+        }
+        shortcut
+            .splits
+            .iter()
+            .map(|split| {
+                make_split(
+                    split.amount,
+                    split
+                        .destination
+                        .as_ref()
+                        .map_or(destination, AccountRef::Name),
+                    split.category.as_ref().or(category),
+                )
+            })
+            .collect::<miette::Result<_>>()?
+    };
+
+    // Firefly requires a group title as soon as a transaction has more than one split.
+    let group_title = (transactions.len() > 1).then_some(description);
+
+    Ok(FireflyStoreTransactionRequest {
+        error_if_duplicate_hash: req
+            .error_if_duplicate_hash
+            .unwrap_or(shortcut.error_if_duplicate_hash),
+        apply_rules: shortcut.apply_rules,
+        fire_webhooks: shortcut.fire_webhooks,
+        group_title,
+        transactions,
     })
 }
 
-fn firefly_req(
+/// Parses a transaction date given either as RFC 3339 or as `YYYY-MM-DD`. Plain dates are taken to
+/// mean local noon, so that the day doesn't change when Firefly converts between time zones.
+fn parse_date(date: &str) -> miette::Result<DateTime<FixedOffset>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Ok(date);
+    }
+
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .into_diagnostic()
+        .with_context(|| format!("parse date {date:?}"))?;
+    let noon = day.and_time(NaiveTime::from_hms_opt(12, 0, 0).expect("noon is a valid time"));
+    Local
+        .from_local_datetime(&noon)
+        .earliest()
+        .map(|date| date.fixed_offset())
+        .ok_or_else(|| miette::miette!("{date} has no local noon"))
+}
+
+/// Replaces `{name}` placeholders in `template` with the corresponding entries of `vars`. Unknown
+/// placeholders are an error rather than being left in the text.
+fn fill_placeholders(template: &str, vars: &HashMap<String, String>) -> miette::Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 1..start + len];
+        let Some(value) = vars.get(name) else {
+            miette::bail!("Unknown placeholder {{{name}}} in {template:?}");
+        };
+        result.push_str(&rest[..start]);
+        result.push_str(value);
+        rest = &rest[start + len + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Sends `request`, retrying up to `config.max_retries` times with exponential backoff if Firefly
+/// can't be reached or answers with a 5xx. Any other response, including a 4xx, is returned as is.
+async fn send_with_retries(
     config: &Config,
+    request: RequestBuilder,
+) -> reqwest::Result<reqwest::Response> {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=config.max_retries {
+        // Requests with streaming bodies can't be sent twice, but we never make those.
+        let Some(this_attempt) = request.try_clone() else {
+            break;
+        };
+
+        let result = this_attempt.send().await;
+        let error = match &result {
+            Ok(response) if response.status().is_server_error() => response.status().to_string(),
+            Err(e) if e.is_connect() => e.to_string(),
+            _ => return result,
+        };
+
+        tracing::warn!(
+            "Firefly request failed ({error}), retrying in {backoff:?} (retry {attempt} of {})",
+            config.max_retries
+        );
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+
+    request.send().await
+}
+
+fn firefly_req(
     client: &Client,
-    pat: &Pat,
+    instance: &Instance,
     method: Method,
     endpoint: &str,
 ) -> RequestBuilder {
     client
-        .request(method, format!("{}api{}", config.firefly_url, endpoint))
-        .bearer_auth(&pat.0)
+        .request(method, format!("{}api{}", instance.firefly_url, endpoint))
+        .bearer_auth(&instance.pat.0)
         .header("accept", "application/vnd.api+json")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config {
+            enabled: true,
+            route: "/firefly".to_string(),
+            firefly_url: Some(Url::parse("https://firefly.example.com/").unwrap()),
+            pat_file: None,
+            pat_env: Some("FIREFLY_PAT".to_string()),
+            pat_file_attempts: 1,
+            pat_file_retry_seconds: 5,
+            instances: Vec::new(),
+            auth_token: None,
+            basic_auth: None,
+            webhook_secret: None,
+            validate_accounts: false,
+            max_retries: 3,
+            budget_cache_seconds: 300,
+            category_cache_seconds: 300,
+            piggy_bank_cache_seconds: 300,
+            user_agent: None,
+            default_budget: None,
+            default_category: None,
+            default_source: Some("Checking Account".to_string()),
+            description_prefix: None,
+            description_suffix: None,
+            shortcuts: Vec::new(),
+        }
+    }
+
+    fn test_shortcut(
+        transaction_type: TransactionType,
+        source: Option<&str>,
+        destination: &str,
+    ) -> Shortcut {
+        Shortcut {
+            shortcut_id: 0,
+            id: None,
+            shortcut_name: "Test".to_string(),
+            shortcut_icon: "⚠".to_string(),
+            name: "Test".to_string(),
+            instance: None,
+            transaction_type,
+            source: source.map(str::to_string),
+            source_id: None,
+            destination: Some(destination.to_string()),
+            destination_id: None,
+            amount: Some(12.5),
+            default_amount: None,
+            amount_hint: None,
+            foreign_amount: None,
+            foreign_currency_code: None,
+            budget: None,
+            category: None,
+            piggy_bank: None,
+            tags: Vec::new(),
+            notes: None,
+            splits: Vec::new(),
+            error_if_duplicate_hash: true,
+            apply_rules: true,
+            fire_webhooks: true,
+            requires_confirmation: false,
+        }
+    }
+
+    /// The JSON sent to Firefly for `shortcut`, with an otherwise empty request.
+    fn request_json(shortcut: &Shortcut) -> serde_json::Value {
+        let req = serde_json::from_value::<AddTransactionRequest>(serde_json::json!({
+            "shortcut_id": 0,
+        }))
+        .unwrap();
+        let request = make_store_transaction_request(shortcut, &test_config(), &req, None).unwrap();
+        serde_json::to_value(request).unwrap()
+    }
+
+    #[test]
+    fn withdrawal_goes_from_asset_to_expense_account() {
+        let shortcut = test_shortcut(TransactionType::Withdrawal, None, "Coffee Shop");
+        let split = &request_json(&shortcut)["transactions"][0];
+        assert_eq!(split["type"], "withdrawal");
+        assert_eq!(split["source_name"], "Checking Account");
+        assert_eq!(split["destination_name"], "Coffee Shop");
+    }
+
+    #[test]
+    fn deposit_goes_from_revenue_to_asset_account() {
+        let shortcut = test_shortcut(
+            TransactionType::Deposit,
+            Some("Employer"),
+            "Checking Account",
+        );
+        let split = &request_json(&shortcut)["transactions"][0];
+        assert_eq!(split["type"], "deposit");
+        assert_eq!(split["source_name"], "Employer");
+        assert_eq!(split["destination_name"], "Checking Account");
+    }
+
+    #[test]
+    fn deposit_ignores_default_source() {
+        let shortcut = test_shortcut(TransactionType::Deposit, None, "Checking Account");
+        let req = serde_json::from_value::<AddTransactionRequest>(serde_json::json!({
+            "shortcut_id": 0,
+        }))
+        .unwrap();
+        assert!(make_store_transaction_request(&shortcut, &test_config(), &req, None).is_err());
+    }
+
+    #[test]
+    fn transfer_goes_between_asset_accounts() {
+        let shortcut = test_shortcut(TransactionType::Transfer, None, "Savings Account");
+        let split = &request_json(&shortcut)["transactions"][0];
+        assert_eq!(split["type"], "transfer");
+        assert_eq!(split["source_name"], "Checking Account");
+        assert_eq!(split["destination_name"], "Savings Account");
+    }
+
+    fn test_shortcuts(config: Config) -> Shortcuts {
+        Shortcuts {
+            current: Arc::new(ArcSwap::from_pointee(Vec::new())),
+            instance_names: Arc::new(vec![DEFAULT_INSTANCE.to_string()]),
+            config: Arc::new(config),
+        }
+    }
+
+    #[test]
+    fn reload_swaps_in_new_shortcuts() {
+        let shortcuts = test_shortcuts(test_config());
+        let mut config = test_config();
+        config.shortcuts = vec![test_shortcut(
+            TransactionType::Withdrawal,
+            None,
+            "Coffee Shop",
+        )];
+        assert_eq!(shortcuts.reload(config).unwrap(), 1);
+        assert_eq!(
+            shortcuts.current()[0].destination.as_deref(),
+            Some("Coffee Shop")
+        );
+    }
+
+    #[test]
+    fn reload_rejects_changed_defaults() {
+        let mut startup = test_config();
+        startup.default_source = None;
+        let shortcuts = test_shortcuts(startup);
+
+        // The shortcut would only work with the new default-source, which requests wouldn't use.
+        let mut config = test_config();
+        config.shortcuts = vec![test_shortcut(
+            TransactionType::Withdrawal,
+            None,
+            "Coffee Shop",
+        )];
+        assert!(shortcuts.reload(config).is_err());
+
+        let mut config = test_config();
+        config.default_source = None;
+        config.description_prefix = Some("[shortcut]".to_string());
+        assert!(shortcuts.reload(config).is_err());
+        assert!(shortcuts.current().is_empty());
+    }
+
+    /// Whether each account is one of your own, as `validate_accounts` finds them in Firefly.
+    fn test_accounts() -> HashMap<&'static str, bool> {
+        HashMap::from([
+            ("Checking Account", true),
+            ("Savings Account", true),
+            ("Credit Card", true),
+            ("Coffee Shop", false),
+            ("Employer", false),
+        ])
+    }
+
+    fn account_problem_of(shortcut: &Shortcut) -> Option<String> {
+        account_problem(shortcut, &test_config(), &test_accounts())
+    }
+
+    #[test]
+    fn validate_accounts_accepts_matching_account_types() {
+        for shortcut in [
+            test_shortcut(TransactionType::Withdrawal, None, "Coffee Shop"),
+            test_shortcut(TransactionType::Withdrawal, None, "New Shop"),
+            test_shortcut(
+                TransactionType::Deposit,
+                Some("Employer"),
+                "Checking Account",
+            ),
+            test_shortcut(
+                TransactionType::Deposit,
+                Some("New Employer"),
+                "Credit Card",
+            ),
+            test_shortcut(TransactionType::Transfer, None, "Savings Account"),
+        ] {
+            assert_eq!(account_problem_of(&shortcut), None, "{shortcut:?}");
+        }
+    }
+
+    #[test]
+    fn validate_accounts_rejects_deposit_from_own_account() {
+        let shortcut = test_shortcut(
+            TransactionType::Deposit,
+            Some("Savings Account"),
+            "Checking Account",
+        );
+        assert!(account_problem_of(&shortcut).is_some());
+    }
+
+    #[test]
+    fn validate_accounts_rejects_deposit_to_other_account() {
+        for destination in ["Coffee Shop", "Unknown Account"] {
+            let shortcut = test_shortcut(TransactionType::Deposit, Some("Employer"), destination);
+            assert!(account_problem_of(&shortcut).is_some(), "{destination}");
+        }
+    }
+
+    #[test]
+    fn validate_accounts_rejects_transfer_with_other_account() {
+        for (source, destination) in [
+            (None, "Coffee Shop"),
+            (None, "Unknown Account"),
+            (Some("Employer"), "Savings Account"),
+        ] {
+            let shortcut = test_shortcut(TransactionType::Transfer, source, destination);
+            assert!(
+                account_problem_of(&shortcut).is_some(),
+                "{source:?} {destination}"
+            );
+        }
+    }
+
+    #[test]
+    fn validate_accounts_rejects_withdrawal_from_other_account() {
+        for source in ["Employer", "Unknown Account"] {
+            let shortcut = test_shortcut(TransactionType::Withdrawal, Some(source), "Coffee Shop");
+            assert!(account_problem_of(&shortcut).is_some(), "{source}");
+        }
+    }
+
+    #[test]
+    fn validate_accounts_skips_accounts_given_by_id() {
+        let mut shortcut = test_shortcut(TransactionType::Transfer, None, "Coffee Shop");
+        shortcut.destination = None;
+        shortcut.destination_id = Some("12".to_string());
+        shortcut.source_id = Some("3".to_string());
+        assert_eq!(account_problem_of(&shortcut), None);
+    }
+
+    #[test]
+    fn validate_rejects_deposit_from_default_source() {
+        let mut config = test_config();
+        config.shortcuts = vec![test_shortcut(
+            TransactionType::Deposit,
+            Some("Checking Account"),
+            "Savings Account",
+        )];
+        assert!(validate(&config).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_transfer_to_same_account() {
+        let mut config = test_config();
+        config.shortcuts = vec![test_shortcut(
+            TransactionType::Transfer,
+            None,
+            "Checking Account",
+        )];
+        assert!(validate(&config).is_err());
+
+        config.shortcuts = vec![test_shortcut(
+            TransactionType::Transfer,
+            None,
+            "Savings Account",
+        )];
+        validate(&config).unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_budget_on_deposit() {
+        let mut shortcut = test_shortcut(
+            TransactionType::Deposit,
+            Some("Employer"),
+            "Checking Account",
+        );
+        shortcut.budget = Some("Groceries".to_string());
+        let mut config = test_config();
+        config.shortcuts = vec![shortcut];
+        assert!(validate(&config).is_err());
+    }
+}
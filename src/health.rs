@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use axum::{Extension, Json, Router};
+use chrono::{DateTime, Utc};
+
+use crate::firefly_shortcuts;
+
+/// How long the readiness check waits for Firefly before reporting it as unreachable.
+const FIREFLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Without a Firefly `Connection`, because that module is disabled, `/health` doesn't check it.
+pub fn setup(firefly: Option<firefly_shortcuts::Connection>, app: Router) -> Router {
+    app.route("/livez", axum::routing::get(livez))
+        .route("/version", axum::routing::get(version))
+        .route(
+            "/health",
+            axum::routing::get(health).layer(Extension(firefly)),
+        )
+}
+
+async fn livez() -> &'static str {
+    "ok"
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Version {
+    version: &'static str,
+    git_hash: &'static str,
+    /// RFC 3339, or "unknown" if the build didn't record a valid time.
+    build_timestamp: String,
+}
+
+/// Reports what exactly is deployed, from the info recorded by `build.rs`.
+async fn version() -> Json<Version> {
+    let build_timestamp = env!("BUILD_TIMESTAMP")
+        .parse()
+        .ok()
+        .and_then(|seconds| DateTime::<Utc>::from_timestamp(seconds, 0))
+        .map_or_else(|| "unknown".to_string(), |time| time.to_rfc3339());
+
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        build_timestamp,
+    })
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Health {
+    firefly: &'static str,
+}
+
+async fn health(
+    Extension(firefly): Extension<Option<firefly_shortcuts::Connection>>,
+) -> Json<Health> {
+    let Some(firefly) = firefly else {
+        return Json(Health {
+            firefly: "disabled",
+        });
+    };
+    let firefly = match firefly.ping(FIREFLY_TIMEOUT).await {
+        Ok(()) => "ok",
+        Err(e) => {
+            tracing::warn!("Health check could not reach Firefly: {e:?}");
+            "unreachable"
+        }
+    };
+
+    Json(Health { firefly })
+}
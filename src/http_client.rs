@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use miette::{Context, IntoDiagnostic};
+use reqwest::Client;
+
+/// Tuning for the clients the modules use to talk to Firefly and calendars.
+#[derive(knuffel::Decode, Debug)]
+pub struct Config {
+    /// How long to wait for a connection to be established.
+    #[knuffel(child, unwrap(argument), default = 10)]
+    connect_timeout_seconds: u64,
+    /// How long a whole request may take, including reading the response. Defaults to the global
+    /// `request-timeout-seconds`, without any limit if that isn't set either.
+    #[knuffel(child, unwrap(argument))]
+    pub request_timeout_seconds: Option<u64>,
+    /// How many idle connections are kept open per host. Unlimited if not set.
+    #[knuffel(child, unwrap(argument))]
+    pool_max_idle_per_host: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            connect_timeout_seconds: 10,
+            request_timeout_seconds: None,
+            pool_max_idle_per_host: None,
+        }
+    }
+}
+
+pub fn validate(config: &Config) -> miette::Result<()> {
+    if config.connect_timeout_seconds == 0 {
+        miette::bail!("http-client connect-timeout-seconds must be at least 1");
+    }
+    if config.request_timeout_seconds == Some(0) {
+        miette::bail!("http-client request-timeout-seconds must be at least 1");
+    }
+    Ok(())
+}
+
+/// Builds a client with the configured timeouts and pool size.
+pub fn build(config: &Config, user_agent: &str) -> miette::Result<Client> {
+    let mut client = Client::builder()
+        .user_agent(user_agent)
+        .connect_timeout(Duration::from_secs(config.connect_timeout_seconds));
+    if let Some(timeout) = config.request_timeout_seconds {
+        client = client.timeout(Duration::from_secs(timeout));
+    }
+    if let Some(max_idle) = config.pool_max_idle_per_host {
+        client = client.pool_max_idle_per_host(max_idle);
+    }
+    client
+        .build()
+        .into_diagnostic()
+        .context("create reqwest Client")
+}
@@ -0,0 +1,434 @@
+//! Just enough iCalendar (RFC 5545) parsing to filter calendars at the component level.
+//!
+//! Properties keep their original (folded) text, so everything that isn't explicitly removed is
+//! written back out exactly as it came in.
+
+use miette::miette;
+
+#[derive(Debug)]
+pub enum Node {
+    Property(Property),
+    Component(Component),
+}
+
+/// A single content line, e.g. `SUMMARY:Lecture`.
+#[derive(Debug)]
+pub struct Property {
+    /// The property as it appeared in the input, including any line folding, but always with CRLF
+    /// line breaks.
+    raw: String,
+    /// Everything before the value, i.e. the name and any parameters.
+    head: String,
+    /// Upper-cased property name.
+    pub name: String,
+    /// Unfolded (but otherwise unescaped) value.
+    pub value: String,
+}
+
+impl Property {
+    /// Returns the value of the parameter with the given name, without any surrounding quotes.
+    /// Parameters with several values are returned as written, separated by commas.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        let mut in_quotes = false;
+        self.head
+            .split(|c| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c == ';' && !in_quotes
+            })
+            .skip(1)
+            .find_map(|param| {
+                let (param_name, value) = param.split_once('=')?;
+                param_name
+                    .eq_ignore_ascii_case(name)
+                    .then(|| value.trim_matches('"'))
+            })
+    }
+
+    /// Replaces the value, which has to be escaped already. The property is folded again if it
+    /// gets too long.
+    pub fn set_value(&mut self, value: String) {
+        self.raw = fold(&format!("{}:{value}", self.head));
+        self.value = value;
+    }
+
+    /// The value of a TEXT property like `SUMMARY`, with escapes resolved.
+    pub fn text(&self) -> String {
+        let mut text = String::with_capacity(self.value.len());
+        let mut chars = self.value.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                text.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n' | 'N') => text.push('\n'),
+                Some(c @ ('\\' | ';' | ',')) => text.push(c),
+                // Not a valid escape, so it's kept as it is.
+                Some(c) => {
+                    text.push('\\');
+                    text.push(c);
+                }
+                None => text.push('\\'),
+            }
+        }
+        text
+    }
+
+    /// Replaces the value of a TEXT property, escaping it as needed.
+    pub fn set_text(&mut self, text: &str) {
+        let mut value = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '\\' | ';' | ',' => {
+                    value.push('\\');
+                    value.push(c);
+                }
+                '\n' => value.push_str("\\n"),
+                '\r' => {}
+                _ => value.push(c),
+            }
+        }
+        self.set_value(value);
+    }
+}
+
+/// A `BEGIN:NAME` ... `END:NAME` block.
+#[derive(Debug)]
+pub struct Component {
+    /// Upper-cased component name, e.g. `VEVENT`.
+    pub name: String,
+    pub children: Vec<Node>,
+}
+
+impl Component {
+    /// Returns the first property with the given name.
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.children.iter().find_map(|node| match node {
+            Node::Property(p) if p.name == name => Some(p),
+            _ => None,
+        })
+    }
+
+    /// Returns the first property with the given name, for modifying it.
+    pub fn property_mut(&mut self, name: &str) -> Option<&mut Property> {
+        self.children.iter_mut().find_map(|node| match node {
+            Node::Property(p) if p.name == name => Some(p),
+            _ => None,
+        })
+    }
+
+    /// Keeps only the properties for which `keep` returns true.
+    pub fn retain_properties(&mut self, mut keep: impl FnMut(&Property) -> bool) {
+        self.children.retain(|node| match node {
+            Node::Property(p) => keep(p),
+            Node::Component(_) => true,
+        });
+    }
+
+    /// Keeps only the direct subcomponents named `name` for which `keep` returns true. Other
+    /// children are left alone. `keep` may also modify the components it is given.
+    pub fn retain_components(&mut self, name: &str, mut keep: impl FnMut(&mut Component) -> bool) {
+        self.children.retain_mut(|node| match node {
+            Node::Component(c) if c.name == name => keep(c),
+            _ => true,
+        });
+    }
+}
+
+/// Parses a full iCalendar document into its top-level nodes (usually a single `VCALENDAR`).
+pub fn parse(text: &str) -> miette::Result<Vec<Node>> {
+    let mut stack: Vec<Component> = Vec::new();
+    let mut top_level = Vec::new();
+
+    for (raw, unfolded) in content_lines(text) {
+        let property = parse_line(raw, &unfolded)?;
+
+        if property.name == "BEGIN" {
+            stack.push(Component {
+                name: property.value.to_ascii_uppercase(),
+                children: Vec::new(),
+            });
+            continue;
+        }
+
+        let node = if property.name == "END" {
+            let component = stack
+                .pop()
+                .ok_or_else(|| miette!("Unexpected {unfolded:?} outside of any component"))?;
+            if !component.name.eq_ignore_ascii_case(&property.value) {
+                miette::bail!(
+                    "Unexpected {unfolded:?} while inside component {}",
+                    component.name
+                );
+            }
+            Node::Component(component)
+        } else {
+            Node::Property(property)
+        };
+
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(node),
+            None => top_level.push(node),
+        }
+    }
+
+    if let Some(component) = stack.last() {
+        miette::bail!("Component {} is never closed", component.name);
+    }
+
+    Ok(top_level)
+}
+
+/// Writes nodes back out, with CRLF line endings as required by the spec.
+pub fn write(nodes: &[Node]) -> String {
+    fn write_node(node: &Node, out: &mut String) {
+        match node {
+            Node::Property(p) => {
+                out.push_str(&p.raw);
+                out.push_str("\r\n");
+            }
+            Node::Component(c) => {
+                out.push_str("BEGIN:");
+                out.push_str(&c.name);
+                out.push_str("\r\n");
+                for child in &c.children {
+                    write_node(child, out);
+                }
+                out.push_str("END:");
+                out.push_str(&c.name);
+                out.push_str("\r\n");
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for node in nodes {
+        write_node(node, &mut out);
+    }
+    out
+}
+
+/// Splits the text into logical content lines, returning each one both as it appeared in the
+/// input (without the final line break) and unfolded.
+fn content_lines(text: &str) -> Vec<(&str, String)> {
+    let mut lines: Vec<(&str, String)> = Vec::new();
+    // Byte offset of the start of the current logical line within `text`.
+    let mut start = 0;
+    let mut offset = 0;
+
+    for physical in text.split_inclusive('\n') {
+        let line = physical.trim_end_matches(['\r', '\n']);
+        let is_continuation = line.starts_with([' ', '\t']);
+
+        match lines.last_mut() {
+            Some((raw, unfolded)) if is_continuation => {
+                unfolded.push_str(&line[1..]);
+                *raw = &text[start..offset + line.len()];
+            }
+            _ if line.is_empty() => {}
+            _ => {
+                start = offset;
+                lines.push((line, line.to_string()));
+            }
+        }
+
+        offset += physical.len();
+    }
+
+    lines
+}
+
+/// Folds a content line so no physical line is longer than the 75 octets the spec allows, without
+/// splitting any characters.
+fn fold(line: &str) -> String {
+    const MAX_OCTETS: usize = 75;
+
+    let mut out = String::with_capacity(line.len());
+    // Continuation lines start with a space, which counts towards their length.
+    let mut line_len = 0;
+    for c in line.chars() {
+        if line_len + c.len_utf8() > MAX_OCTETS {
+            out.push_str("\r\n ");
+            line_len = 1;
+        }
+        out.push(c);
+        line_len += c.len_utf8();
+    }
+    out
+}
+
+fn parse_line(raw: &str, unfolded: &str) -> miette::Result<Property> {
+    // The value starts at the first colon that isn't inside a quoted parameter value.
+    let mut in_quotes = false;
+    let colon = unfolded
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            c == ':' && !in_quotes
+        })
+        .map(|(i, _)| i)
+        .ok_or_else(|| miette!("Content line without a value: {unfolded:?}"))?;
+
+    let (head, value) = (&unfolded[..colon], &unfolded[colon + 1..]);
+    let name = head.split_once(';').map_or(head, |(name, _)| name);
+
+    Ok(Property {
+        raw: raw.replace("\r\n", "\n").replace('\n', "\r\n"),
+        head: head.to_string(),
+        name: name.to_ascii_uppercase(),
+        value: value.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CALENDAR: &str = "BEGIN:VCALENDAR\r\n\
+        VERSION:2.0\r\n\
+        PRODID:-//Example//Calendar//EN\r\n\
+        X-WR-CALNAME:Lectures\r\n\
+        BEGIN:VTIMEZONE\r\n\
+        TZID:Europe/Copenhagen\r\n\
+        BEGIN:DAYLIGHT\r\n\
+        TZOFFSETFROM:+0100\r\n\
+        TZOFFSETTO:+0200\r\n\
+        DTSTART:19700329T020000\r\n\
+        RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU\r\n\
+        END:DAYLIGHT\r\n\
+        END:VTIMEZONE\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:1@example.com\r\n\
+        DTSTART;TZID=Europe/Copenhagen:20240701T120000\r\n\
+        SUMMARY:A lecture with a summary long enough that it had to be folded by the\r\n \
+        \x20server\\, twice even\\; which is a lot\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+
+    /// The first component, which has to be there.
+    fn component(nodes: &[Node]) -> &Component {
+        nodes
+            .iter()
+            .find_map(|node| match node {
+                Node::Component(c) => Some(c),
+                Node::Property(_) => None,
+            })
+            .unwrap()
+    }
+
+    fn property(line: &str) -> Property {
+        parse_line(line, line).unwrap()
+    }
+
+    #[test]
+    fn round_trips_unchanged() {
+        let nodes = parse(CALENDAR).unwrap();
+        assert_eq!(write(&nodes), CALENDAR);
+
+        let vcalendar = component(&nodes);
+        assert_eq!(vcalendar.name, "VCALENDAR");
+        assert_eq!(
+            vcalendar.property("X-WR-CALNAME").unwrap().value,
+            "Lectures"
+        );
+        let names: Vec<_> = vcalendar
+            .children
+            .iter()
+            .filter_map(|node| match node {
+                Node::Component(c) => Some(c.name.as_str()),
+                Node::Property(_) => None,
+            })
+            .collect();
+        assert_eq!(names, ["VTIMEZONE", "VEVENT"]);
+    }
+
+    #[test]
+    fn unfolds_lines() {
+        let nodes = parse(CALENDAR).unwrap();
+        let Some(Node::Component(event)) = component(&nodes).children.last() else {
+            panic!("no event");
+        };
+        assert_eq!(
+            event.property("SUMMARY").unwrap().text(),
+            "A lecture with a summary long enough that it had to be folded by the server, twice \
+             even; which is a lot"
+        );
+    }
+
+    #[test]
+    fn accepts_lf_line_endings() {
+        let nodes = parse(&CALENDAR.replace("\r\n", "\n")).unwrap();
+        assert_eq!(write(&nodes), CALENDAR);
+    }
+
+    #[test]
+    fn rejects_unbalanced_components() {
+        assert!(parse("BEGIN:VCALENDAR\r\n").is_err());
+        assert!(parse("END:VCALENDAR\r\n").is_err());
+        assert!(parse("BEGIN:VCALENDAR\r\nEND:VEVENT\r\n").is_err());
+        assert!(parse("BEGIN:VCALENDAR\r\nNO VALUE\r\nEND:VCALENDAR\r\n").is_err());
+    }
+
+    #[test]
+    fn folds_long_lines() {
+        let line = format!("SUMMARY:{}", "a".repeat(200));
+        let folded = fold(&line);
+        assert!(folded.split("\r\n").all(|physical| physical.len() <= 75));
+        assert_eq!(content_lines(&folded)[0].1, line);
+        assert_eq!(fold("SUMMARY:short"), "SUMMARY:short");
+    }
+
+    #[test]
+    fn folds_multibyte_lines_between_characters() {
+        let line = format!("SUMMARY:{}", "æøå€🎉".repeat(20));
+        let folded = fold(&line);
+        // Splitting a character would have made this invalid UTF-8 to begin with, so check that
+        // no line is too long and that unfolding gets the original back.
+        assert!(folded.split("\r\n").all(|physical| physical.len() <= 75));
+        assert!(folded.split("\r\n").count() > 1);
+        assert_eq!(content_lines(&folded)[0].1, line);
+    }
+
+    #[test]
+    fn set_value_folds_again() {
+        let mut summary = property("SUMMARY;LANGUAGE=da:Kort");
+        summary.set_value("ø".repeat(50));
+        assert!(summary.raw.contains("\r\n "));
+        assert!(summary.raw.starts_with("SUMMARY;LANGUAGE=da:"));
+        assert_eq!(
+            content_lines(&summary.raw)[0].1,
+            format!("SUMMARY;LANGUAGE=da:{}", "ø".repeat(50))
+        );
+    }
+
+    #[test]
+    fn quoted_parameters_can_contain_colons_and_semicolons() {
+        let attendee = property(
+            "ATTENDEE;CN=\"Doe; John\";DIR=\"ldap://example.com:6666\":mailto:j@example.com",
+        );
+        assert_eq!(attendee.name, "ATTENDEE");
+        assert_eq!(attendee.value, "mailto:j@example.com");
+        assert_eq!(attendee.param("CN"), Some("Doe; John"));
+        assert_eq!(attendee.param("dir"), Some("ldap://example.com:6666"));
+        assert_eq!(attendee.param("ROLE"), None);
+    }
+
+    #[test]
+    fn text_resolves_escapes() {
+        let summary = property("SUMMARY:a\\, b\\; c\\\\d\\nnext\\Nline\\x");
+        assert_eq!(summary.text(), "a, b; c\\d\nnext\nline\\x");
+    }
+
+    #[test]
+    fn set_text_escapes() {
+        let mut summary = property("SUMMARY:old");
+        summary.set_text("a, b; c\\d\nnext");
+        assert_eq!(summary.value, "a\\, b\\; c\\\\d\\nnext");
+        assert_eq!(summary.raw, "SUMMARY:a\\, b\\; c\\\\d\\nnext");
+        assert_eq!(summary.text(), "a, b; c\\d\nnext");
+    }
+}
@@ -0,0 +1,52 @@
+use std::{io::ErrorKind, path::PathBuf, sync::Arc};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Router,
+};
+
+use crate::error::ApiError;
+
+const DEFAULT_INDEX: &str = concat!(
+    "reasonable-excuse ",
+    env!("CARGO_PKG_VERSION"),
+    "\n\nNothing to see here. Set index-file in the config to serve a page of your own.\n"
+);
+
+/// Serves `index_file` at `/`, or a short text page if there is none. The file is read on every
+/// request, so it can be edited without a restart.
+pub fn setup(index_file: Option<PathBuf>, app: Router) -> Router {
+    app.route(
+        "/",
+        axum::routing::get(index).layer(Extension(Arc::new(index_file))),
+    )
+}
+
+async fn index(
+    Extension(index_file): Extension<Arc<Option<PathBuf>>>,
+) -> Result<Response, ApiError> {
+    let Some(path) = index_file.as_ref() else {
+        return Ok((
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            DEFAULT_INDEX,
+        )
+            .into_response());
+    };
+
+    match tokio::fs::read(path).await {
+        Ok(contents) => {
+            let content_type =
+                mime_guess::from_path(path).first_or(mime_guess::mime::TEXT_HTML_UTF_8);
+            Ok(([(header::CONTENT_TYPE, content_type.to_string())], contents).into_response())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            tracing::warn!(path = ?path, "Configured index file does not exist");
+            Err(StatusCode::NOT_FOUND.into())
+        }
+        Err(e) => {
+            tracing::error!(path = ?path, error = ?e, "Error reading index file");
+            Err(ApiError::internal("Could not read the index page"))
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use ipnet::IpNet;
+use miette::{Context, IntoDiagnostic};
+
+/// Only lets through requests from clients within one of the `cidrs`. Without any, every client is
+/// allowed.
+pub fn setup(cidrs: &[String], app: Router) -> miette::Result<Router> {
+    if cidrs.is_empty() {
+        return Ok(app);
+    }
+
+    let networks = parse(cidrs)?;
+    Ok(app.layer(axum::middleware::from_fn_with_state(
+        Arc::new(networks),
+        check,
+    )))
+}
+
+pub fn parse(cidrs: &[String]) -> miette::Result<Vec<IpNet>> {
+    cidrs
+        .iter()
+        .map(|cidr| {
+            cidr.parse::<IpNet>()
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Invalid allow-cidr {cidr:?}"))
+        })
+        .collect()
+}
+
+async fn check(State(networks): State<Arc<Vec<IpNet>>>, request: Request, next: Next) -> Response {
+    let Some(ConnectInfo(client_addr)) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .copied()
+    else {
+        return next.run(request).await;
+    };
+
+    // IPv4 clients of a server listening on IPv6 show up as IPv4-mapped addresses.
+    let ip = client_addr.ip().to_canonical();
+    if !networks.iter().any(|network| network.contains(&ip)) {
+        tracing::warn!(%client_addr, "Rejecting request from outside the allowed networks");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    next.run(request).await
+}
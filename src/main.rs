@@ -1,45 +1,401 @@
-use std::net::SocketAddr;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use axum::{
-    http::{HeaderValue, Method},
-    Router,
+    error_handling::HandleErrorLayer,
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+    BoxError, Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
 use miette::{IntoDiagnostic, Result, WrapErr};
 use tokio::net::TcpListener;
-use tower_http::cors::CorsLayer;
+use tokio_util::sync::CancellationToken;
+use tower::{timeout::TimeoutLayer, ServiceBuilder};
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    trace::{DefaultOnResponse, TraceLayer},
+};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
+mod auth;
 mod calendar;
+mod error;
 mod firefly_shortcuts;
+mod health;
+mod http_client;
+mod ics;
+mod index;
+mod ip_allowlist;
+mod metrics;
+mod otlp;
+mod pcs;
+mod rate_limit;
+mod request_id;
 mod upload;
 
 #[derive(knuffel::Decode, Debug)]
 struct Config {
+    /// Either `host:port`, or `unix:/path/to.sock` to listen on a Unix domain socket.
     #[knuffel(child, unwrap(argument))]
     address: String,
+    #[knuffel(children(name = "allow-origin"), unwrap(argument))]
+    allow_origins: Vec<String>,
+    /// Networks in CIDR notation that clients have to be in. Without any, everyone is allowed.
+    #[knuffel(children(name = "allow-cidr"), unwrap(argument))]
+    allow_cidrs: Vec<String>,
+    /// Requests taking longer than this, including uploads, are aborted with a 504. Also used as
+    /// the timeout for requests to upstream services, unless `http-client` sets its own. No limit
+    /// if not set.
+    #[knuffel(child, unwrap(argument))]
+    request_timeout_seconds: Option<u64>,
+    /// Compress responses for clients that accept it. Can be turned off for debugging.
+    #[knuffel(child, unwrap(argument), default = true)]
+    compression: bool,
+    /// Maximum number of requests a single client IP may make per minute. Unlimited if not set.
+    #[knuffel(child, unwrap(argument))]
+    requests_per_minute: Option<u32>,
+    /// After a shutdown signal, how long to wait for in-flight requests before exiting anyway.
+    #[knuffel(child, unwrap(argument), default = 30)]
+    shutdown_timeout_seconds: u64,
+    /// Serve over HTTPS instead of plain HTTP.
+    #[knuffel(child)]
+    tls: Option<TlsConfig>,
+    /// Export traces to this OTLP/HTTP endpoint, e.g. `http://localhost:4318/v1/traces`, in
+    /// addition to logging them.
     #[knuffel(child, unwrap(argument))]
-    allow_origin: Option<String>,
+    otlp_endpoint: Option<String>,
+    /// Served at `/`, with the content type guessed from its extension. A short built-in page is
+    /// shown without it.
+    #[knuffel(child, unwrap(argument))]
+    index_file: Option<PathBuf>,
+    /// Timeouts and connection pooling for requests to upstream services.
+    #[knuffel(child, default)]
+    http_client: http_client::Config,
     #[knuffel(child)]
     upload: upload::Config,
     #[knuffel(child)]
     firefly_shortcuts: firefly_shortcuts::Config,
+    #[knuffel(children(name = "calendar"))]
+    calendars: Vec<calendar::Config>,
+    /// The module is left out if there is no `pcs` block, so older configs keep working.
     #[knuffel(child)]
-    calendar: calendar::Config,
+    pcs: Option<pcs::Config>,
+}
+
+#[derive(knuffel::Decode, Debug)]
+struct TlsConfig {
+    /// PEM file with the certificate chain.
+    #[knuffel(child, unwrap(argument))]
+    cert_file: PathBuf,
+    /// PEM file with the private key.
+    #[knuffel(child, unwrap(argument))]
+    key_file: PathBuf,
+}
+
+const CONFIG_ENV: &str = "REASONABLE_EXCUSE_CONFIG";
+
+/// Printed by `--print-example-config`. Has to be kept up to date with the config structs.
+const EXAMPLE_CONFIG: &str = include_str!("../example-config.kdl");
+
+struct Args {
+    config_path: PathBuf,
+    /// Only validate the config and exit, without starting the server.
+    check_config: bool,
+    /// Print an example config and exit.
+    print_example_config: bool,
+}
+
+/// Parses the command line. The config path from `--config`/`-c` takes precedence over the
+/// `REASONABLE_EXCUSE_CONFIG` env var, which takes precedence over `./config.kdl`.
+fn parse_args() -> Result<Args> {
+    let mut path = None;
+    let mut check_config = false;
+    let mut print_example_config = false;
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg
+            .into_string()
+            .map_err(|arg| miette::miette!("Invalid argument {arg:?}"))?;
+        match arg.as_str() {
+            "--config" | "-c" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| miette::miette!("{arg} requires a path"))?;
+                path = Some(PathBuf::from(value));
+            }
+            "--check-config" => check_config = true,
+            "--print-example-config" => print_example_config = true,
+            _ => match arg.strip_prefix("--config=") {
+                Some(value) => path = Some(PathBuf::from(value)),
+                None => miette::bail!("Unknown argument {arg:?}"),
+            },
+        }
+    }
+
+    let config_path = path
+        .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("./config.kdl"));
+    Ok(Args {
+        config_path,
+        check_config,
+        print_example_config,
+    })
 }
 
-fn read_config() -> Result<Config> {
-    let path = "./config.kdl";
+fn read_config(path: &Path) -> Result<Config> {
     let text = std::fs::read_to_string(path)
         .into_diagnostic()
-        .wrap_err_with(|| format!("Failed to read config file at {}", path))?;
-    let config = knuffel::parse::<Config>(path, &text).wrap_err("Failed to parse config file")?;
+        .wrap_err_with(|| format!("Failed to read config file at {}", path.display()))?;
+    let text = interpolate_env(&text).wrap_err("Failed to substitute environment variables")?;
+    let config = knuffel::parse::<Config>(&path.to_string_lossy(), &text)
+        .wrap_err("Failed to parse config file")?;
     Ok(config)
 }
 
+/// Runs every check that doesn't need the network or any secrets, for `--check-config`. Setting up
+/// the modules does the same checks again.
+fn validate_config(config: &Config) -> Result<()> {
+    let _ = parse_allow_origin(&config.allow_origins)?;
+    ip_allowlist::parse(&config.allow_cidrs)?;
+    if config.requests_per_minute == Some(0) {
+        miette::bail!("requests-per-minute must be at least 1");
+    }
+    validate_unix_socket(config)?;
+    if let Some(endpoint) = &config.otlp_endpoint {
+        endpoint
+            .parse::<axum::http::Uri>()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid otlp-endpoint {endpoint:?}"))?;
+    }
+    if let Some(tls) = &config.tls {
+        for file in [&tls.cert_file, &tls.key_file] {
+            if !file.is_file() {
+                miette::bail!("TLS file {} does not exist", file.display());
+            }
+        }
+    }
+
+    http_client::validate(&config.http_client)?;
+    upload::validate(&config.upload).context("upload module")?;
+    firefly_shortcuts::validate(&config.firefly_shortcuts).context("firefly_shortcuts module")?;
+    calendar::validate(&config.calendars).context("calendar module")?;
+    if let Some(pcs) = &config.pcs {
+        pcs::validate(pcs).context("pcs module")?;
+    }
+    Ok(())
+}
+
+/// Checks the options that can't be used when listening on a Unix socket. Its clients have no
+/// address, so anything telling clients apart by theirs would treat them all the same.
+fn validate_unix_socket(config: &Config) -> Result<()> {
+    if !config.address.starts_with("unix:") {
+        return Ok(());
+    }
+    if config.tls.is_some() {
+        miette::bail!("TLS is not supported when listening on a Unix socket");
+    }
+    if !config.allow_cidrs.is_empty() {
+        miette::bail!("allow-cidr can't be used when listening on a Unix socket");
+    }
+    if config.requests_per_minute.is_some() {
+        miette::bail!("requests-per-minute can't be used when listening on a Unix socket");
+    }
+    Ok(())
+}
+
+/// Replaces `${VAR}` in the string values of the config with the value of the environment variable
+/// `VAR`, which has to be set. `${VAR:-default}` falls back to `default` instead, and `$${` stands
+/// for a literal `${`. Comments are left alone, as are references that aren't valid variable
+/// names, like the `${1}` of a regex replacement.
+fn interpolate_env(text: &str) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("//") {
+            let end = rest.find('\n').unwrap_or(rest.len());
+            result.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if rest.starts_with("/*") {
+            let end = block_comment_len(rest)?;
+            result.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if c == '"' {
+            let end = string_len(rest)?;
+            result.push('"');
+            result.push_str(&interpolate_string(&rest[1..end - 1], None)?);
+            result.push('"');
+            rest = &rest[end..];
+        } else if let Some(hashes) = raw_string_hashes(&result, rest) {
+            let start = 2 + hashes;
+            let closing = format!("\"{}", "#".repeat(hashes));
+            let Some(len) = rest[start..].find(&closing) else {
+                miette::bail!("Unclosed raw string in config");
+            };
+            result.push_str(&rest[..start]);
+            result.push_str(&interpolate_string(
+                &rest[start..start + len],
+                Some(&closing),
+            )?);
+            result.push_str(&closing);
+            rest = &rest[start + len + closing.len()..];
+        } else {
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Ok(result)
+}
+
+/// The length of the (possibly nested) block comment `text` starts with.
+fn block_comment_len(text: &str) -> Result<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < text.len() {
+        if text[i..].starts_with("/*") {
+            depth += 1;
+            i += 2;
+        } else if text[i..].starts_with("*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return Ok(i);
+            }
+        } else {
+            i += text[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    miette::bail!("Unclosed comment in config");
+}
+
+/// The length of the quoted string `text` starts with, including both quotes.
+fn string_len(text: &str) -> Result<usize> {
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => return Ok(i + 1),
+            _ => {}
+        }
+    }
+    miette::bail!("Unclosed string in config");
+}
+
+/// If `rest` starts a raw string like `r#"…"#`, returns its number of `#`s. `before` is the text
+/// preceding it, to tell raw strings from identifiers ending in `r`.
+fn raw_string_hashes(before: &str, rest: &str) -> Option<usize> {
+    let after_r = rest.strip_prefix('r')?;
+    let hashes = after_r.len() - after_r.trim_start_matches('#').len();
+    let starts_token = !before
+        .chars()
+        .next_back()
+        .is_some_and(|c| c.is_alphanumeric() || "-_.".contains(c));
+    (starts_token && after_r[hashes..].starts_with('"')).then_some(hashes)
+}
+
+/// Substitutes environment variables in the contents of a string. Values are escaped for a quoted
+/// string, or for raw strings ending in `raw_closing`, rejected if they contain it.
+fn interpolate_string(text: &str, raw_closing: Option<&str>) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            result.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let Some(len) = after.find('}') else {
+            miette::bail!("Unclosed ${{ in config");
+        };
+        let reference = &after[..len];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        if !is_env_var_name(name) {
+            result.push_str("${");
+            rest = after;
+            continue;
+        }
+
+        match (std::env::var(name), default) {
+            (Ok(value), _) => match raw_closing {
+                None => result.push_str(&escape_kdl_string(&value)),
+                Some(closing) if value.contains(closing) => {
+                    miette::bail!(
+                        "Env var {name} can't be used in a raw string ending in {closing}"
+                    )
+                }
+                Some(_) => result.push_str(&value),
+            },
+            // Written in the config itself, so it's already escaped.
+            (Err(_), Some(default)) => result.push_str(default),
+            (Err(e), None) => {
+                return Err(e)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Config references env var {name}"))
+            }
+        }
+        rest = &after[len + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn is_env_var_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Escapes `value` so a quoted KDL string containing it reads back as `value`.
+fn escape_kdl_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Set through the environment rather than the config, so that config errors are logged in the
+    // right format too.
+    let fmt_layer = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => tracing_subscriber::fmt::layer().json().boxed(),
+        Ok("text") | Err(_) => tracing_subscriber::fmt::layer().boxed(),
+        Ok(other) => miette::bail!("Unknown LOG_FORMAT {other:?}, expected \"text\" or \"json\""),
+    };
+    let (otlp_layer, otlp_handle) = otlp::layer();
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(otlp_layer)
+        .with(fmt_layer)
         .with(
             EnvFilter::try_new(
                 std::env::var("RUST_LOG").unwrap_or("info,reasonable_excuse=trace".to_string()),
@@ -48,47 +404,402 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    let config = read_config()?;
+    let Args {
+        config_path,
+        check_config,
+        print_example_config,
+    } = parse_args()?;
+    if print_example_config {
+        print!("{EXAMPLE_CONFIG}");
+        return Ok(());
+    }
+    let config = read_config(&config_path)?;
+
+    if check_config {
+        validate_config(&config)?;
+        println!(
+            "Config at {} is valid: {} shortcut(s), {} calendar(s)",
+            config_path.display(),
+            config.firefly_shortcuts.shortcut_count(),
+            config.calendars.len()
+        );
+        return Ok(());
+    }
+
     tracing::info!("Starting with config {:?}", config);
+    validate_unix_socket(&config)?;
+
+    // Dropped at the end of main, which flushes the remaining spans.
+    let otlp_guard = config
+        .otlp_endpoint
+        .as_deref()
+        .map(|endpoint| otlp::setup(endpoint, &otlp_handle))
+        .transpose()
+        .context("set up OTLP export")?;
+
+    let request_timeout = config.request_timeout_seconds.map(Duration::from_secs);
+    let mut http_client = config.http_client;
+    http_client.request_timeout_seconds = http_client
+        .request_timeout_seconds
+        .or(config.request_timeout_seconds);
+
+    // Cancelled once a shutdown signal arrives, to stop background tasks along with the server.
+    let shutdown_token = CancellationToken::new();
+
+    let load_config: firefly_shortcuts::ConfigLoader = {
+        let config_path = config_path.clone();
+        Arc::new(move || read_config(&config_path).map(|config| config.firefly_shortcuts))
+    };
 
-    let app = Router::new();
-    let app = upload::setup(config.upload, app).context("set up upload module")?;
-    let app = firefly_shortcuts::setup(config.firefly_shortcuts, app)
-        .context("set up firefly_shortcuts module")?;
-    let mut app = calendar::setup(config.calendar, app).context("set up calendar module")?;
+    let app = metrics::setup(Router::new()).context("set up metrics")?;
+    let app = upload::setup(config.upload, shutdown_token.clone(), app)
+        .context("set up upload module")?;
+    let (app, firefly, shortcuts) =
+        firefly_shortcuts::setup(config.firefly_shortcuts, &http_client, load_config, app)
+            .await
+            .context("set up firefly_shortcuts module")?;
+    let app = health::setup(firefly, app);
+    let app = index::setup(config.index_file, app);
+    let (app, calendars) =
+        calendar::setup(config.calendars, &http_client, app).context("set up calendar module")?;
+    let mut app = match config.pcs {
+        Some(pcs) => pcs::setup(pcs, app).context("set up pcs module")?,
+        None => app,
+    };
 
-    if let Some(allow_origin) = &config.allow_origin {
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(config_path, shortcuts, calendars));
+    #[cfg(not(unix))]
+    let _ = (config_path, shortcuts, calendars);
+
+    if let Some(timeout) = request_timeout {
         app = app.layer(
-            CorsLayer::new()
-                .allow_methods([Method::GET, Method::PUT])
-                .allow_origin(
-                    allow_origin
-                        .parse::<HeaderValue>()
-                        .into_diagnostic()
-                        .context("parse allow-origin value")?,
-                ),
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: BoxError| async {
+                    StatusCode::GATEWAY_TIMEOUT
+                }))
+                .layer(TimeoutLayer::new(timeout)),
         );
     }
 
+    if !config.allow_origins.is_empty() {
+        let allow_origin = parse_allow_origin(&config.allow_origins)?;
+        app = app.layer(cors(allow_origin));
+    }
+
+    if config.compression {
+        app = app.layer(CompressionLayer::new());
+    }
+
+    if let Some(requests_per_minute) = config.requests_per_minute {
+        app = rate_limit::setup(requests_per_minute, app).context("set up rate limiting")?;
+    }
+
+    app = ip_allowlist::setup(&config.allow_cidrs, app).context("set up IP allowlist")?;
+
+    // One span per request, which the handler spans are nested in, and one access log line when the
+    // response is sent.
+    app = app.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(|request: &Request| {
+                let client_addr = request
+                    .extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map_or_else(
+                        || "unknown".to_string(),
+                        |ConnectInfo(addr)| addr.to_string(),
+                    );
+                let request_id = request
+                    .headers()
+                    .get(&request_id::X_REQUEST_ID)
+                    .and_then(|id| id.to_str().ok())
+                    .unwrap_or_default();
+                tracing::info_span!(
+                    "request",
+                    method = %request.method(),
+                    uri = %request.uri(),
+                    client_addr = %client_addr,
+                    request_id = %request_id,
+                )
+            })
+            .on_response(DefaultOnResponse::new().level(tracing::Level::INFO)),
+    );
+    app = app.layer(axum::middleware::from_fn(request_id::middleware));
+
+    // Added last so it also sees the responses produced by the other layers, like timeouts.
+    app = app.layer(axum::middleware::from_fn(metrics::track));
+
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    app = app.layer(axum::middleware::from_fn_with_state(
+        in_flight.clone(),
+        count_in_flight,
+    ));
+    let shutdown = shutdown_with_timeout(
+        Duration::from_secs(config.shutdown_timeout_seconds),
+        in_flight,
+        shutdown_token,
+        otlp_guard.as_ref().map(otlp::Guard::flusher),
+    );
+
+    if let Some(path) = config.address.strip_prefix("unix:") {
+        return serve_unix(Path::new(path), app, shutdown).await;
+    }
+
     let addr = config
         .address
         .parse::<SocketAddr>()
         .into_diagnostic()
         .wrap_err_with(|| format!("Could not parse server address: {}", config.address))?;
 
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    if let Some(tls) = &config.tls {
+        let tls_config = RustlsConfig::from_pem_file(&tls.cert_file, &tls.key_file)
+            .await
+            .into_diagnostic()
+            .wrap_err_with(|| {
+                format!(
+                    "Could not load TLS certificate {} with key {}",
+                    tls.cert_file.display(),
+                    tls.key_file.display()
+                )
+            })?;
+
+        let handle = axum_server::Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                shutdown.await;
+                handle.graceful_shutdown(None);
+            }
+        });
+
+        tracing::info!("listening on {} (TLS)", addr);
+        return axum_server::bind_rustls(addr, tls_config)
+            .handle(handle)
+            .serve(app)
+            .await
+            .into_diagnostic()
+            .wrap_err("Could not serve over TLS");
+    }
+
     tracing::info!("listening on {}", addr);
     let listener = TcpListener::bind(addr)
         .await
         .into_diagnostic()
         .wrap_err("Could not bind to address!")?;
 
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .with_graceful_shutdown(shutdown_signal())
-    .await
-    .into_diagnostic()
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await
+        .into_diagnostic()
+}
+
+/// Serves `app` on a Unix domain socket at `path` until `shutdown` completes.
+#[cfg(unix)]
+async fn serve_unix(path: &Path, app: Router, shutdown: impl Future<Output = ()>) -> Result<()> {
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto, graceful::GracefulShutdown},
+        service::TowerToHyperService,
+    };
+    use tokio::net::UnixListener;
+
+    // A socket file left behind by a previous run that didn't shut down cleanly would make binding
+    // fail.
+    match std::fs::remove_file(path) {
+        Ok(()) => tracing::info!("Removed stale socket {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Could not remove stale socket {}", path.display()))
+        }
+    }
+
+    let listener = UnixListener::bind(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not bind to socket {}", path.display()))?;
+
+    // There is no peer address on a Unix socket, but the handlers all expect one. Options that
+    // would tell clients apart by it are rejected by `validate_unix_socket`.
+    let app = app.layer(axum::Extension(ConnectInfo(SocketAddr::from((
+        [0, 0, 0, 0],
+        0,
+    )))));
+
+    tracing::info!("listening on {}", path.display());
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let socket = tokio::select! {
+            result = listener.accept() => match result {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    tracing::error!("Failed to accept connection: {e}");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            () = &mut shutdown => break,
+        };
+
+        let connection = auto::Builder::new(TokioExecutor::new())
+            .serve_connection_with_upgrades(
+                TokioIo::new(socket),
+                TowerToHyperService::new(app.clone()),
+            )
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("Connection error: {e}");
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+    if let Err(e) = std::fs::remove_file(path) {
+        tracing::warn!("Could not remove socket {}: {e}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_path: &Path, _app: Router, _shutdown: impl Future<Output = ()>) -> Result<()> {
+    miette::bail!("Unix sockets are not supported on this platform")
+}
+
+/// Re-reads the config on every SIGHUP and swaps in the parts that can be changed at runtime.
+#[cfg(unix)]
+async fn reload_on_sighup(
+    config_path: PathBuf,
+    shortcuts: Option<firefly_shortcuts::Shortcuts>,
+    calendars: calendar::Calendars,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("Failed to install SIGHUP handler, config reloading is disabled: {e}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        tracing::info!("SIGHUP received, reloading config");
+        let config = match read_config(&config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("Failed to reload config, keeping the old one: {e:?}");
+                continue;
+            }
+        };
+
+        if let Some(shortcuts) = &shortcuts {
+            match shortcuts.reload(config.firefly_shortcuts) {
+                Ok(count) => tracing::info!("Reloaded firefly_shortcuts: {count} shortcuts"),
+                Err(e) => {
+                    tracing::error!("Failed to reload shortcuts, keeping the old ones: {e:?}")
+                }
+            }
+        }
+        match calendars.reload(config.calendars) {
+            Ok(count) => tracing::info!("Reloaded calendar: filters of {count} calendars"),
+            Err(e) => tracing::error!("Failed to reload calendar filters: {e:?}"),
+        }
+        tracing::info!(
+            "Everything else (address, TLS, timeouts, upload, pcs and other firefly_shortcuts or \
+             calendar settings) requires a restart, and changing the defaults for shortcuts is \
+             rejected until then"
+        );
+    }
+}
+
+/// Keeps track of how many requests are currently being handled.
+async fn count_in_flight(
+    State(in_flight): State<Arc<AtomicUsize>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    struct Guard(Arc<AtomicUsize>);
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    in_flight.fetch_add(1, Ordering::Relaxed);
+    let _guard = Guard(in_flight);
+    next.run(request).await
+}
+
+/// Lets browsers use every route from the allowed origins. Protected routes need the
+/// `Authorization` header, which browsers only send after a preflight allowing it.
+fn cors(allow_origin: AllowOrigin) -> CorsLayer {
+    CorsLayer::new()
+        .allow_methods([
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::CONTENT_RANGE,
+            firefly_shortcuts::IDEMPOTENCY_KEY,
+            request_id::X_REQUEST_ID,
+        ])
+        .expose_headers([request_id::X_REQUEST_ID, header::RETRY_AFTER])
+        .allow_origin(allow_origin)
+}
+
+fn parse_allow_origin(origins: &[String]) -> Result<AllowOrigin> {
+    if origins.iter().any(|origin| origin == "*") {
+        return Ok(AllowOrigin::any());
+    }
+
+    let origins = origins
+        .iter()
+        .map(|origin| {
+            origin
+                .parse::<HeaderValue>()
+                .into_diagnostic()
+                .with_context(|| format!("parse allow-origin value {origin:?}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(AllowOrigin::list(origins))
+}
+
+/// Completes on a shutdown signal, like `shutdown_signal`, but also makes sure the process exits
+/// `timeout` later even if some requests never finish. Cancels `token` when the signal arrives.
+/// Exiting that way skips all destructors, so spans are flushed with `otlp` first.
+async fn shutdown_with_timeout(
+    timeout: Duration,
+    in_flight: Arc<AtomicUsize>,
+    token: CancellationToken,
+    otlp: Option<otlp::Flusher>,
+) {
+    shutdown_signal().await;
+    token.cancel();
+
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        tracing::warn!(
+            "{} requests still in flight after {timeout:?}, exiting anyway",
+            in_flight.load(Ordering::Relaxed)
+        );
+        if let Some(otlp) = otlp {
+            otlp.flush();
+        }
+        std::process::exit(1);
+    });
 }
 
 async fn shutdown_signal() {
@@ -118,3 +829,107 @@ async fn shutdown_signal() {
 
     tracing::info!("signal received, starting graceful shutdown");
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cors_allows_authenticated_requests() {
+        let allow_origin = parse_allow_origin(&["http://localhost:8000".to_string()]).unwrap();
+        let app = Router::new()
+            .route("/transaction/:id", axum::routing::delete(|| async {}))
+            .layer(cors(allow_origin));
+
+        let preflight = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/transaction/1")
+            .header(header::ORIGIN, "http://localhost:8000")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "DELETE")
+            .header(
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "authorization,idempotency-key",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(preflight).await.unwrap();
+
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:8000"
+        );
+        let allowed = |name| headers[name].to_str().unwrap().to_ascii_lowercase();
+        assert!(allowed(header::ACCESS_CONTROL_ALLOW_METHODS).contains("delete"));
+        let allowed_headers = allowed(header::ACCESS_CONTROL_ALLOW_HEADERS);
+        assert!(allowed_headers.contains("authorization"));
+        assert!(allowed_headers.contains("idempotency-key"));
+
+        let request = Request::builder()
+            .method(Method::DELETE)
+            .uri("/transaction/1")
+            .header(header::ORIGIN, "http://localhost:8000")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let exposed = response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+            .to_str()
+            .unwrap()
+            .to_ascii_lowercase();
+        assert!(exposed.contains("x-request-id"));
+        assert!(exposed.contains("retry-after"));
+    }
+
+    #[test]
+    fn interpolate_env_only_substitutes_in_strings() {
+        std::env::set_var("INTERPOLATE_TEST_TOKEN", "secret");
+        let text = "// ${INTERPOLATE_TEST_TOKEN}\n\
+                    /* ${INTERPOLATE_TEST_TOKEN} */\n\
+                    auth-token \"${INTERPOLATE_TEST_TOKEN}\"\n\
+                    raw r#\"a ${INTERPOLATE_TEST_TOKEN} b\"#\n";
+        assert_eq!(
+            interpolate_env(text).unwrap(),
+            "// ${INTERPOLATE_TEST_TOKEN}\n\
+             /* ${INTERPOLATE_TEST_TOKEN} */\n\
+             auth-token \"secret\"\n\
+             raw r#\"a secret b\"#\n"
+        );
+    }
+
+    #[test]
+    fn interpolate_env_escapes_values() {
+        std::env::set_var("INTERPOLATE_TEST_QUOTED", "a\"b\\c");
+        assert_eq!(
+            interpolate_env("x \"${INTERPOLATE_TEST_QUOTED}\"").unwrap(),
+            "x \"a\\\"b\\\\c\""
+        );
+        assert!(interpolate_env("x r#\"${INTERPOLATE_TEST_QUOTED}\"#").is_ok());
+        std::env::set_var("INTERPOLATE_TEST_RAW", "a\"#b");
+        assert!(interpolate_env("x r#\"${INTERPOLATE_TEST_RAW}\"#").is_err());
+    }
+
+    #[test]
+    fn interpolate_env_leaves_escapes_and_regex_groups() {
+        assert_eq!(
+            interpolate_env("filter \"(.*)\" \"$1 ${1} $${HOME}\"").unwrap(),
+            "filter \"(.*)\" \"$1 ${1} ${HOME}\""
+        );
+    }
+
+    #[test]
+    fn interpolate_env_keeps_example_config() {
+        assert_eq!(interpolate_env(EXAMPLE_CONFIG).unwrap(), EXAMPLE_CONFIG);
+    }
+
+    #[test]
+    fn interpolate_env_uses_defaults() {
+        assert_eq!(
+            interpolate_env("x \"${INTERPOLATE_TEST_UNSET:-fallback}\"").unwrap(),
+            "x \"fallback\""
+        );
+        assert!(interpolate_env("x \"${INTERPOLATE_TEST_UNSET}\"").is_err());
+    }
+}
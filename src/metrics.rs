@@ -0,0 +1,62 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+    Extension, Router,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use miette::{Context, IntoDiagnostic};
+
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Installs the global metrics recorder and adds a `/metrics` route in the Prometheus text format.
+pub fn setup(app: Router) -> miette::Result<Router> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("http_request_duration_seconds".to_string()),
+            DURATION_BUCKETS,
+        )
+        .into_diagnostic()
+        .context("set request duration buckets")?
+        .install_recorder()
+        .into_diagnostic()
+        .context("install metrics recorder")?;
+
+    Ok(app.route(
+        "/metrics",
+        axum::routing::get(render).layer(Extension(handle)),
+    ))
+}
+
+async fn render(Extension(handle): Extension<PrometheusHandle>) -> String {
+    handle.render()
+}
+
+/// Middleware counting requests and their durations per route, method and status.
+pub async fn track(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    // Label by the route pattern rather than the actual path, to keep the number of series bounded.
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let method = request.method().to_string();
+
+    let response = next.run(request).await;
+
+    let labels = [
+        ("route", route),
+        ("method", method),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    ::metrics::counter!("http_requests_total", &labels).increment(1);
+    ::metrics::histogram!("http_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+
+    response
+}
@@ -0,0 +1,77 @@
+use miette::{IntoDiagnostic, WrapErr};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    trace::{SdkTracerProvider, Tracer},
+    Resource,
+};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{reload, Registry};
+
+/// The OTLP layer, which is only filled in once the config has been read, since logging is set up
+/// before that.
+pub type Layer = reload::Layer<Option<OpenTelemetryLayer<Registry, Tracer>>, Registry>;
+pub type Handle = reload::Handle<Option<OpenTelemetryLayer<Registry, Tracer>>, Registry>;
+
+pub fn layer() -> (Layer, Handle) {
+    reload::Layer::new(None)
+}
+
+/// Flushes any spans that haven't been exported yet when dropped.
+pub struct Guard(SdkTracerProvider);
+
+impl Guard {
+    /// A handle to flush spans with when the process has to exit without dropping the guard.
+    pub fn flusher(&self) -> Flusher {
+        Flusher(self.0.clone())
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            tracing::warn!("Failed to flush OTLP spans: {e}");
+        }
+    }
+}
+
+/// Flushes spans that haven't been exported yet on request, unlike `Guard` without shutting down.
+#[derive(Clone)]
+pub struct Flusher(SdkTracerProvider);
+
+impl Flusher {
+    pub fn flush(&self) {
+        if let Err(e) = self.0.force_flush() {
+            tracing::warn!("Failed to flush OTLP spans: {e}");
+        }
+    }
+}
+
+/// Starts exporting spans over OTLP/HTTP to `endpoint`, the full URL of the collector's traces
+/// endpoint. Spans are exported in batches in the background, so the returned guard has to be kept
+/// around until shutdown.
+pub fn setup(endpoint: &str, handle: &Handle) -> miette::Result<Guard> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .into_diagnostic()
+        .wrap_err("Failed to create OTLP exporter")?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    handle
+        .reload(Some(OpenTelemetryLayer::new(tracer)))
+        .into_diagnostic()
+        .wrap_err("Failed to install OTLP layer")?;
+
+    tracing::info!("Exporting traces to {endpoint}");
+    Ok(Guard(provider))
+}
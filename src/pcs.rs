@@ -0,0 +1,216 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, Query},
+    handler::Handler,
+    http::StatusCode,
+    Extension, Router,
+};
+use chrono::{DateTime, Local, TimeDelta};
+use tokio::sync::RwLock;
+
+use crate::auth::{self, Auth, BasicAuthConfig, Secret};
+
+#[derive(knuffel::Decode, Debug)]
+pub struct Config {
+    /// When false, the module is left out entirely.
+    #[knuffel(child, unwrap(argument), default = true)]
+    enabled: bool,
+    #[knuffel(child, unwrap(argument))]
+    route: String,
+    /// How many of the most recent requests to keep around.
+    #[knuffel(child, unwrap(argument), default = 50)]
+    max_requests: usize,
+    /// Maximum size of a captured request body in bytes. Larger requests are rejected with 413.
+    #[knuffel(child, unwrap(argument), default = 65536)]
+    max_body_bytes: usize,
+    /// If set, viewing and clearing the captured requests requires this as a bearer token. Anyone
+    /// can still send requests to be captured.
+    #[knuffel(child, unwrap(argument, str))]
+    auth_token: Option<Secret>,
+    /// Alternatively, require HTTP Basic auth with these credentials.
+    #[knuffel(child)]
+    basic_auth: Option<BasicAuthConfig>,
+}
+
+/// Bodies longer than this many characters are cut off when listing requests.
+const MAX_DISPLAY_CHARS: usize = 2000;
+
+/// A request captured by `post`.
+#[derive(Debug)]
+struct Request {
+    time: DateTime<Local>,
+    client_ip: IpAddr,
+    body: String,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    last_requests: VecDeque<Request>,
+}
+
+impl State {
+    /// Adds a request, dropping the oldest ones to keep at most `max_requests`.
+    fn push(&mut self, request: Request, max_requests: usize) {
+        while self.last_requests.len() >= max_requests {
+            self.last_requests.pop_front();
+        }
+        self.last_requests.push_back(request);
+    }
+}
+
+pub fn validate(config: &Config) -> miette::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    if config.max_requests == 0 {
+        miette::bail!("pcs max-requests must be at least 1");
+    }
+    Auth::new(config.auth_token.as_ref(), config.basic_auth.as_ref())?;
+    Ok(())
+}
+
+pub fn setup(config: Config, app: Router) -> miette::Result<Router> {
+    if !config.enabled {
+        tracing::info!("pcs module is disabled");
+        return Ok(app);
+    }
+    validate(&config)?;
+
+    let auth = Auth::new(config.auth_token.as_ref(), config.basic_auth.as_ref())?;
+    let config = Arc::new(config);
+    let state = Arc::new(RwLock::new(State::default()));
+
+    Ok(app
+        .route(
+            &config.route,
+            axum::routing::post(post.layer(DefaultBodyLimit::max(config.max_body_bytes))),
+        )
+        // Captured bodies and client IPs aren't for everyone to see.
+        .route(
+            &config.route,
+            auth::protect(axum::routing::get(get).delete(clear), &auth),
+        )
+        .layer(Extension(config))
+        .layer(Extension(state)))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct GetQuery {
+    /// Only show this many of the most recent requests.
+    limit: Option<usize>,
+    /// Only show requests from the last this many seconds.
+    since: Option<u32>,
+}
+
+#[tracing::instrument(skip(state))]
+async fn get(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<RwLock<State>>>,
+    Query(query): Query<GetQuery>,
+) -> String {
+    tracing::info!("GET pcs");
+
+    let state = state.read().await;
+    let cutoff = query
+        .since
+        .map(|since| Local::now() - TimeDelta::seconds(since.into()));
+    let requests = state
+        .last_requests
+        .iter()
+        .filter(|req| cutoff.is_none_or(|cutoff| req.time >= cutoff))
+        .collect::<Vec<_>>();
+    let skip = query
+        .limit
+        .map_or(0, |limit| requests.len().saturating_sub(limit));
+
+    let mut output = String::new();
+    for req in &requests[skip..] {
+        let body = match req.body.char_indices().nth(MAX_DISPLAY_CHARS) {
+            Some((end, _)) => format!("{}…", &req.body[..end]),
+            None => req.body.clone(),
+        };
+        let _ = writeln!(
+            output,
+            "--- {} from {} ---\n{}\n",
+            req.time.to_rfc3339(),
+            req.client_ip,
+            body
+        );
+    }
+    output
+}
+
+#[tracing::instrument(skip(config, state, body))]
+async fn post(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<RwLock<State>>>,
+    body: Bytes,
+) -> StatusCode {
+    tracing::info!("pcs request with {} bytes", body.len());
+
+    let Ok(body) = String::from_utf8(body.into()) else {
+        tracing::warn!("Rejecting pcs request that isn't valid UTF-8");
+        return StatusCode::BAD_REQUEST;
+    };
+
+    state.write().await.push(
+        Request {
+            time: Local::now(),
+            client_ip: client_addr.ip(),
+            body,
+        },
+        config.max_requests,
+    );
+
+    StatusCode::OK
+}
+
+#[tracing::instrument(skip(state))]
+async fn clear(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<RwLock<State>>>,
+) -> String {
+    let mut state = state.write().await;
+    let cleared = state.last_requests.len();
+    state.last_requests.clear();
+
+    tracing::info!("Cleared {cleared} captured pcs requests");
+    cleared.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn push_evicts_oldest_requests() {
+        let mut state = State::default();
+        for i in 0..200 {
+            let request = Request {
+                time: Local::now(),
+                client_ip: Ipv4Addr::LOCALHOST.into(),
+                body: i.to_string(),
+            };
+            state.push(request, 50);
+        }
+
+        assert_eq!(state.last_requests.len(), 50);
+        let bodies = state
+            .last_requests
+            .iter()
+            .map(|req| req.body.as_str())
+            .collect::<Vec<_>>();
+        let expected = (150..200).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(bodies, expected);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use tokio::sync::RwLock;
+
+/// Requests are counted in fixed windows of this length.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Requests seen from one client in the current window.
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+struct Limiter {
+    requests_per_minute: u32,
+    clients: RwLock<HashMap<IpAddr, Window>>,
+}
+
+/// Limits every client IP to `requests_per_minute` requests, answering any beyond that with a 429.
+pub fn setup(requests_per_minute: u32, app: Router) -> miette::Result<Router> {
+    if requests_per_minute == 0 {
+        miette::bail!("requests-per-minute must be at least 1");
+    }
+
+    let limiter = Arc::new(Limiter {
+        requests_per_minute,
+        clients: RwLock::default(),
+    });
+
+    // Windows of clients that went quiet would otherwise stay around forever.
+    tokio::spawn({
+        let limiter = limiter.clone();
+        async move {
+            let mut interval = tokio::time::interval(WINDOW);
+            loop {
+                interval.tick().await;
+                let mut clients = limiter.clients.write().await;
+                clients.retain(|_, window| window.start.elapsed() < WINDOW);
+            }
+        }
+    });
+
+    Ok(app.layer(axum::middleware::from_fn_with_state(limiter, limit)))
+}
+
+async fn limit(State(limiter): State<Arc<Limiter>>, request: Request, next: Next) -> Response {
+    let Some(ConnectInfo(client_addr)) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .copied()
+    else {
+        return next.run(request).await;
+    };
+
+    let retry_after = {
+        let mut clients = limiter.clients.write().await;
+        let window = clients.entry(client_addr.ip()).or_insert(Window {
+            start: Instant::now(),
+            count: 0,
+        });
+        if window.start.elapsed() >= WINDOW {
+            *window = Window {
+                start: Instant::now(),
+                count: 0,
+            };
+        }
+
+        window.count += 1;
+        (window.count > limiter.requests_per_minute)
+            .then(|| WINDOW.saturating_sub(window.start.elapsed()))
+    };
+
+    match retry_after {
+        Some(retry_after) => {
+            tracing::warn!(%client_addr, "Rate limit exceeded");
+            // Round up, so clients don't come back a moment too early.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, seconds.to_string())],
+            )
+                .into_response()
+        }
+        None => next.run(request).await,
+    }
+}
@@ -1,44 +1,293 @@
-use std::{io::ErrorKind, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Cursor, ErrorKind},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
 use axum::{
-    body::Bytes,
-    extract::{ConnectInfo, DefaultBodyLimit, Multipart},
-    http::StatusCode,
-    Extension, Router,
+    body::{Body, HttpBody},
+    extract::{multipart::Field, ConnectInfo, DefaultBodyLimit, Multipart, Path},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json, Router,
+};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Local,
 };
+use image::{ImageFormat, ImageReader};
 use miette::{miette, Context, IntoDiagnostic};
-use tokio::fs::OpenOptions;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Semaphore,
+};
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
 use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::{
+    auth::{self, Auth, BasicAuthConfig, Secret},
+    error::ApiError,
+};
 
 #[derive(knuffel::Decode, Debug)]
 pub struct Config {
+    /// When false, the module is left out entirely, so one config can be shared between hosts that
+    /// don't all need it.
+    #[knuffel(child, unwrap(argument), default = true)]
+    enabled: bool,
     #[knuffel(child, unwrap(argument))]
     route: String,
     #[knuffel(child, unwrap(argument))]
     target_dir: PathBuf,
+    /// Create `target_dir`, including any missing parents, if it doesn't exist yet.
+    #[knuffel(child, unwrap(argument), default)]
+    create_target_dir: bool,
+    /// Length of generated names, between 1 and `MAX_FILENAME_LENGTH`.
     #[knuffel(child, unwrap(argument))]
     filename_length: usize,
+    /// Store uploads under the (sanitized) name sent by the client instead of a random one.
+    #[knuffel(child, unwrap(argument), default)]
+    keep_name: bool,
+    /// Extension, without the dot, for files uploaded without any file name. They get a random
+    /// name even with `keep_name`. Such uploads are rejected if not set.
+    #[knuffel(child, unwrap(argument))]
+    default_extension: Option<String>,
+    /// If set, uploads require this as a bearer token.
+    #[knuffel(child, unwrap(argument, str))]
+    auth_token: Option<Secret>,
+    /// Alternatively, require HTTP Basic auth with these credentials.
+    #[knuffel(child)]
+    basic_auth: Option<BasicAuthConfig>,
+    /// File extensions that may be uploaded, compared case-insensitively. Empty allows anything.
+    #[knuffel(children(name = "allowed-extension"), unwrap(argument))]
+    allowed_extensions: Vec<String>,
+    /// With `allowed-extension`s set, uploads whose content doesn't look like their extension are
+    /// rejected. This also rejects files whose type can't be detected from their content at all.
+    #[knuffel(child, unwrap(argument), default)]
+    strict_content_type: bool,
+    /// URL under which `target-dir` is served. If set, uploads return the full URL to the file
+    /// instead of just its name.
+    #[knuffel(child, unwrap(argument))]
+    public_base_url: Option<String>,
+    /// Characters random file names are made of. Defaults to `[a-zA-Z0-9]`.
+    #[knuffel(child, unwrap(argument))]
+    alphabet: Option<String>,
+    /// Maximum size of an upload request in bytes. Uploads are streamed to disk, but the limit is
+    /// still enforced while streaming and larger uploads fail with 413. Unlimited if not set.
+    #[knuffel(child, unwrap(argument))]
+    max_bytes: Option<usize>,
+    /// Maximum size of all uploads together, in bytes. Uploads that would go over it fail with
+    /// 507. Unlimited if not set.
+    #[knuffel(child, unwrap(argument))]
+    max_total_bytes: Option<u64>,
+    /// Sort uploads into subdirectories by upload date, named with this `strftime` pattern, e.g.
+    /// `%Y/%m`. The returned names include the subdirectory.
+    #[knuffel(child, unwrap(argument))]
+    subdir_format: Option<String>,
+    /// How many uploads may be in progress at once. Any more are rejected with 503.
+    #[knuffel(child, unwrap(argument), default = 4)]
+    max_concurrent_uploads: usize,
+    /// Maximum width and height of the thumbnails served under `{route}/thumb/{name}`.
+    #[knuffel(child, unwrap(argument), default = 256)]
+    thumbnail_size: u32,
+    /// Uploads last modified more than this many days ago are deleted, checked once a day. Kept
+    /// forever if not set.
+    #[knuffel(child, unwrap(argument))]
+    retention_days: Option<u32>,
+    /// Resumable upload sessions without any activity for this long are dropped, along with what
+    /// was uploaded so far.
+    #[knuffel(child, unwrap(argument), default = 3600)]
+    session_ttl_seconds: u64,
+}
+
+/// Even with a small alphabet, more than this doesn't make names any harder to guess in practice.
+const MAX_FILENAME_LENGTH: usize = 64;
+
+/// Checks the config without changing anything. A missing target dir is fine if it would be
+/// created.
+pub fn validate(config: &Config) -> miette::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    if !config.create_target_dir || config.target_dir.exists() {
+        let upload_target_meta = std::fs::metadata(&config.target_dir)
+            .into_diagnostic()
+            .wrap_err("Failed to check metadata of upload target dir")?;
+
+        if !upload_target_meta.is_dir() {
+            return Err(miette!(
+                "Upload target path {} is not a directory!",
+                config.target_dir.display()
+            ));
+        }
+    }
+
+    if !(1..=MAX_FILENAME_LENGTH).contains(&config.filename_length) {
+        miette::bail!(
+            "Upload filename-length must be between 1 and {MAX_FILENAME_LENGTH}, got {}",
+            config.filename_length
+        );
+    }
+
+    if let Some(alphabet) = &config.alphabet {
+        if alphabet.is_empty() {
+            miette::bail!("Upload alphabet must not be empty");
+        }
+        let mut seen = HashSet::new();
+        if let Some(duplicate) = alphabet.chars().find(|&c| !seen.insert(c)) {
+            miette::bail!("Upload alphabet contains {duplicate:?} more than once");
+        }
+    }
+
+    if let Some(format) = &config.subdir_format {
+        if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+            miette::bail!("Upload subdir-format {format:?} is not a valid strftime pattern");
+        }
+        // The date can't add anything dangerous, so checking one is enough.
+        let sample = Local::now().format(format).to_string();
+        let is_safe = !sample.is_empty()
+            && std::path::Path::new(&sample)
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if !is_safe {
+            miette::bail!(
+                "Upload subdir-format {format:?} must give a relative path, got {sample:?}"
+            );
+        }
+    }
+
+    if config.max_concurrent_uploads == 0 {
+        miette::bail!("Upload max-concurrent-uploads must be at least 1");
+    }
+
+    if config.session_ttl_seconds == 0 {
+        miette::bail!("Upload session-ttl-seconds must be at least 1");
+    }
+
+    if let Some(extension) = &config.default_extension {
+        if extension.is_empty() || extension.contains(['.', '/', '\\', '\0']) {
+            miette::bail!("Upload default-extension {extension:?} is not a valid extension");
+        }
+    }
+
+    Auth::new(config.auth_token.as_ref(), config.basic_auth.as_ref())?;
+    Ok(())
 }
 
-pub fn setup(config: Config, app: Router) -> miette::Result<Router> {
+pub fn setup(config: Config, shutdown: CancellationToken, app: Router) -> miette::Result<Router> {
+    if !config.enabled {
+        tracing::info!("upload module is disabled");
+        return Ok(app);
+    }
     let config = Arc::new(config);
 
-    let upload_target_meta = std::fs::metadata(&config.target_dir)
-        .into_diagnostic()
-        .wrap_err("Failed to check metadata of upload target dir")?;
+    // Anything already at that path is left for the check below.
+    if config.create_target_dir && !config.target_dir.exists() {
+        std::fs::create_dir_all(&config.target_dir)
+            .into_diagnostic()
+            .wrap_err_with(|| {
+                format!(
+                    "Failed to create upload target dir {}",
+                    config.target_dir.display()
+                )
+            })?;
+    }
 
-    if !upload_target_meta.is_dir() {
-        return Err(miette!(
-            "Upload target path {} is not a directory!",
-            config.target_dir.display()
-        ));
+    validate(&config)?;
+
+    let usage = Arc::new(DiskUsage::new(&config)?);
+
+    if let Some(days) = config.retention_days {
+        let config = config.clone();
+        let usage = usage.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            // The first tick completes immediately, so old files are cleaned up on startup too.
+            let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => delete_expired(&config, &usage, days).await,
+                    _ = shutdown.cancelled() => break,
+                }
+            }
+        });
+    }
+
+    let sessions = Arc::new(Sessions::default());
+    {
+        let config = config.clone();
+        let sessions = sessions.clone();
+        let usage = usage.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SESSION_SWEEP_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => sessions.delete_expired(&config, &usage).await,
+                    _ = shutdown.cancelled() => break,
+                }
+            }
+        });
     }
 
+    let body_limit = match config.max_bytes {
+        Some(max_bytes) => DefaultBodyLimit::max(max_bytes),
+        // This is only accessible internally anyway; I want to be able to upload large files.
+        None => DefaultBodyLimit::disable(),
+    };
+
+    let auth = Auth::new(config.auth_token.as_ref(), config.basic_auth.as_ref())?;
     Ok(app
         .route(&config.route, axum::routing::get(get))
-        .route(&config.route, axum::routing::post(post))
-        // This is only accessible internally anyway; I want to be able to upload large files.
-        .layer(DefaultBodyLimit::disable())
+        .route(
+            &config.route,
+            auth::protect(axum::routing::post(post), &auth),
+        )
+        .route(
+            &format!("{}/session", config.route),
+            auth::protect(axum::routing::post(create_session), &auth),
+        )
+        .route(
+            &format!("{}/session/:id", config.route),
+            auth::protect(
+                axum::routing::get(session_offset).patch(append_to_session),
+                &auth,
+            ),
+        )
+        .route(
+            &format!("{}/session/:id/complete", config.route),
+            auth::protect(axum::routing::post(complete_session), &auth),
+        )
+        .route(
+            &format!("{}/list", config.route),
+            auth::protect(axum::routing::get(list), &auth),
+        )
+        // Under a prefix of their own, so names like `list` or `session` can't clash with the
+        // other routes.
+        .route(
+            &format!("{}/file/*name", config.route),
+            auth::protect(
+                axum::routing::get(file).head(file_head).delete(delete),
+                &auth,
+            ),
+        )
+        .route(
+            &format!("{}/thumb/*name", config.route),
+            auth::protect(axum::routing::get(thumbnail), &auth),
+        )
+        .layer(body_limit)
+        .layer(Extension(Arc::new(Semaphore::new(
+            config.max_concurrent_uploads,
+        ))))
+        .layer(Extension(sessions))
+        .layer(Extension(usage))
         .layer(Extension(config)))
 }
 
@@ -48,83 +297,1161 @@ async fn get(ConnectInfo(client_addr): ConnectInfo<SocketAddr>) -> &'static str
     "POST to this address to upload files"
 }
 
-#[tracing::instrument(skip(body, config))]
+#[tracing::instrument(skip(body, config, uploads, usage))]
 async fn post(
     ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
     Extension(config): Extension<Arc<Config>>,
-    body: Multipart,
-) -> Result<String, StatusCode> {
+    Extension(uploads): Extension<Arc<Semaphore>>,
+    Extension(usage): Extension<Arc<DiskUsage>>,
+    mut body: Multipart,
+) -> Result<String, ApiError> {
     tracing::info!("Upload request");
 
-    let (original_name, bytes) = get_file_name_and_bytes(body).await?;
+    // Held until the upload is done. Clients retrying a stalled upload shouldn't be able to pile up
+    // enough of them to saturate the disk.
+    let Ok(_permit) = uploads.try_acquire() else {
+        tracing::warn!("Rejecting upload, too many in progress");
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many uploads in progress, try again later",
+        ));
+    };
+
+    // Every field is stored as it arrives, so if a later one fails, the earlier ones have to be
+    // removed again to reject the request as a whole.
+    let mut stored = Vec::new();
+    let result = async {
+        while let Some(field) = next_file_field(&mut body).await? {
+            stored.push(store_file(&config, &usage, field).await?);
+        }
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        for (_, path, size) in &stored {
+            match tokio::fs::remove_file(path).await {
+                Ok(()) => usage.release(*size),
+                Err(e) => {
+                    tracing::error!(path = ?path, error = ?e, "Error removing file of failed upload")
+                }
+            }
+        }
+        return Err(e);
+    }
+    if stored.is_empty() {
+        return Err(ApiError::bad_request(
+            "Expected a multipart field named \"file\"",
+        ));
+    }
+
+    let names = stored
+        .into_iter()
+        .map(|(name, _, _)| public_name(&config, name));
+    Ok(names.collect::<Vec<_>>().join("\n"))
+}
+
+/// What clients are told an upload is called: its name, or its full URL with `public_base_url`.
+fn public_name(config: &Config, name: String) -> String {
+    match &config.public_base_url {
+        Some(base_url) => format!("{}/{name}", base_url.trim_end_matches('/')),
+        None => name,
+    }
+}
 
+/// Writes a single uploaded file to the target dir, returning the name it was stored under, its
+/// path and its size. Nothing is left behind if this fails.
+async fn store_file(
+    config: &Config,
+    usage: &DiskUsage,
+    mut field: Field<'_>,
+) -> Result<(String, PathBuf, u64), ApiError> {
+    let original_name = field.file_name().map(sanitize_filename);
+    let NewUpload {
+        name,
+        extension,
+        path,
+        part_path,
+        mut file,
+    } = create_upload(config, original_name.as_deref()).await?;
+
+    // Counts towards the disk usage as soon as it's received, so concurrent uploads can't go over
+    // the limit together.
+    let mut size = 0;
+    let written = async {
+        // The start of the file, to detect its actual type from.
+        let mut head = Vec::new();
+        while let Some(chunk) = field.chunk().await.map_err(|e| {
+            tracing::warn!(error = ?e, "Error reading upload body");
+            // This is also where exceeding the body limit surfaces, as 413.
+            ApiError::new(e.status(), e.body_text())
+        })? {
+            if head.len() < SNIFF_BYTES {
+                let missing = SNIFF_BYTES - head.len();
+                head.extend_from_slice(&chunk[..missing.min(chunk.len())]);
+            }
+            usage.reserve(chunk.len() as u64)?;
+            size += chunk.len() as u64;
+            file.write_all(&chunk).await.map_err(|e| {
+                tracing::error!(error = ?e, "Error writing file");
+                ApiError::internal("Could not write the file")
+            })?;
+        }
+        file.flush().await.map_err(|e| {
+            tracing::error!(error = ?e, "Error writing file");
+            ApiError::internal("Could not write the file")
+        })?;
+
+        if !config.allowed_extensions.is_empty() {
+            check_content_type(&head, &extension, config.strict_content_type)?;
+        }
+        Ok::<_, ApiError>(())
+    }
+    .instrument(tracing::info_span!("Writing file", path = ?part_path))
+    .await;
+    drop(file);
+    if let Err(e) = finish_upload(&part_path, &path, written).await {
+        usage.release(size);
+        return Err(e);
+    }
+
+    tracing::info!(
+        path = ?path,
+        "Uploaded file {} with {} bytes",
+        original_name.as_deref().unwrap_or("without a name"),
+        size
+    );
+    Ok((name, path, size))
+}
+
+/// An upload whose name has been decided, but whose content is still being written to
+/// `part_path`.
+struct NewUpload {
+    /// Relative to the target dir, as given to clients.
+    name: String,
+    extension: String,
+    path: PathBuf,
+    part_path: PathBuf,
+    file: File,
+}
+
+/// Decides the name of an upload sent with `original_name`, and creates the temporary file it is
+/// written to.
+async fn create_upload(
+    config: &Config,
+    original_name: Option<&str>,
+) -> Result<NewUpload, ApiError> {
     // We want to preserve the original file extension, while replacing the rest of the file name
     // with a random short name.
-    let extension = original_name
-        .rsplit_once('.')
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .1;
+    let extension = match (original_name, &config.default_extension) {
+        (Some(original_name), _) => {
+            original_name
+                .rsplit_once('.')
+                .ok_or_else(|| ApiError::bad_request("The file name has no extension"))?
+                .1
+        }
+        // Some clients send a file field without any file name.
+        (None, Some(default_extension)) => default_extension.as_str(),
+        (None, None) => return Err(ApiError::bad_request("The file field has no file name")),
+    };
 
-    loop {
-        let mut name = generate_name(config.filename_length);
-        name.push('.');
-        name.push_str(&extension);
+    if !config.allowed_extensions.is_empty()
+        && !config
+            .allowed_extensions
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(extension))
+    {
+        tracing::warn!("Rejecting upload with extension {extension}");
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Files with extension {extension:?} are not allowed"),
+        ));
+    }
 
-        let mut path = config.target_dir.clone();
-        path.push(&name);
+    // The body is streamed straight to disk, so the name has to be decided before reading any of it.
+    let subdir = match &config.subdir_format {
+        Some(format) => {
+            let subdir = Local::now().format(format).to_string();
+            let dir = config.target_dir.join(&subdir);
+            tokio::fs::create_dir_all(&dir).await.map_err(|e| {
+                tracing::error!(path = ?dir, error = ?e, "Error creating upload subdir");
+                ApiError::internal("Could not create the file")
+            })?;
+            Some(subdir)
+        }
+        None => None,
+    };
+    let dir = match &subdir {
+        Some(subdir) => config.target_dir.join(subdir),
+        None => config.target_dir.clone(),
+    };
+    // Names are given to clients relative to the target dir.
+    let relative_name = |name: &str| match &subdir {
+        Some(subdir) => format!("{subdir}/{name}"),
+        None => name.to_string(),
+    };
 
-        let mut file = match OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&path)
-            .await
-        {
-            // happened to get a random path that already exists, try again
-            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+    // Uploads are written to a temporary file next to their final path first, and only moved there
+    // once complete, so nothing reading the target dir ever sees half-written files.
+    let keep_name = original_name.filter(|_| config.keep_name);
+    let (name, path, part_path, file) = if let Some(original_name) = keep_name {
+        if original_name.is_empty() {
+            tracing::warn!("Upload has no usable file name");
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "The file name can't be used",
+            ));
+        }
+
+        let path = dir.join(original_name);
+        match create_part_file(&path).await {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                tracing::warn!(path = ?path, "Upload would overwrite existing file");
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    format!("A file named {original_name:?} already exists"),
+                ));
+            }
             Err(e) => {
                 tracing::error!(path = ?path, error = ?e, "Error opening file for upload");
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                return Err(ApiError::internal("Could not create the file"));
             }
-            Ok(f) => f,
-        };
+            Ok((part_path, f)) => (relative_name(original_name), path, part_path, f),
+        }
+    } else {
+        loop {
+            let mut name = generate_name(config.filename_length, config.alphabet.as_deref());
+            name.push('.');
+            name.push_str(extension);
+
+            let path = dir.join(&name);
+
+            match create_part_file(&path).await {
+                // happened to get a random path that already exists, try again
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    tracing::error!(path = ?path, error = ?e, "Error opening file for upload");
+                    return Err(ApiError::internal("Could not create the file"));
+                }
+                Ok((part_path, f)) => break (relative_name(&name), path, part_path, f),
+            }
+        }
+    };
+
+    Ok(NewUpload {
+        name,
+        extension: extension.to_string(),
+        path,
+        part_path,
+        file,
+    })
+}
 
-        tokio::io::copy_buf(&mut bytes.as_ref(), &mut file)
-            .instrument(tracing::info_span!("Writing file", path = ?path))
+/// Moves a completely written upload from `part_path` to `path`. If writing it failed, as told by
+/// `written`, or it can't be moved, the temporary file is removed instead.
+async fn finish_upload<T>(
+    part_path: &std::path::Path,
+    path: &std::path::Path,
+    written: Result<T, ApiError>,
+) -> Result<T, ApiError> {
+    let result = match written {
+        Ok(written) => tokio::fs::rename(part_path, path)
             .await
+            .map(|()| written)
             .map_err(|e| {
-                tracing::error!(error = ?e, "Error writing file");
-                StatusCode::INTERNAL_SERVER_ERROR
+                tracing::error!(path = ?path, error = ?e, "Error moving upload into place");
+                ApiError::internal("Could not write the file")
+            }),
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        if let Err(e) = tokio::fs::remove_file(part_path).await {
+            tracing::error!(path = ?part_path, error = ?e, "Error removing partial upload");
+        }
+    }
+    result
+}
+
+/// How often expired upload sessions are looked for.
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A resumable upload, which is appended to piece by piece until the client completes it.
+struct Session {
+    /// Where the content is collected, in the target dir. Dotfiles don't show up in `list`.
+    path: PathBuf,
+    /// How much has been received so far, which is where the next piece has to start.
+    size: u64,
+    /// The total size, once a client has told us.
+    total: Option<u64>,
+    last_active: Instant,
+}
+
+/// The resumable uploads in progress, by id. Each session is locked while a piece is appended to
+/// it, so concurrent requests for the same session can't interleave their writes.
+#[derive(Default)]
+struct Sessions(std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<Session>>>>);
+
+impl Sessions {
+    fn get(&self, id: &str) -> Result<Arc<tokio::sync::Mutex<Session>>, ApiError> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "No such upload session"))
+    }
+
+    /// Drops sessions that have been idle for longer than the configured TTL, and removes their
+    /// files. Session files left over from before a restart are removed once they are as old.
+    async fn delete_expired(&self, config: &Config, usage: &DiskUsage) {
+        let ttl = Duration::from_secs(config.session_ttl_seconds);
+
+        let expired = {
+            let mut sessions = self.0.lock().unwrap();
+            let mut expired = Vec::new();
+            sessions.retain(|id, session| {
+                // Sessions that are being written to right now are clearly not idle.
+                let Ok(session) = session.try_lock() else {
+                    return true;
+                };
+                if session.last_active.elapsed() < ttl {
+                    return true;
+                }
+                tracing::info!(path = ?session.path, "Upload session {id} expired");
+                expired.push((session.path.clone(), session.size));
+                false
+            });
+            expired
+        };
+        for (path, size) in expired {
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => usage.release(size),
+                Err(e) => {
+                    tracing::error!(path = ?path, error = ?e, "Error removing expired upload session")
+                }
+            }
+        }
+
+        let Some(cutoff) = SystemTime::now().checked_sub(ttl) else {
+            return;
+        };
+        let mut entries = match tokio::fs::read_dir(&config.target_dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::error!(error = ?e, "Error listing upload target dir for session cleanup");
+                return;
+            }
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let is_session = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(".session-") && name.ends_with(".part"));
+            if !is_session {
+                continue;
+            }
+            let is_stale = entry
+                .metadata()
+                .await
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified < cutoff);
+            if is_stale {
+                let path = entry.path();
+                match tokio::fs::remove_file(&path).await {
+                    Ok(()) => tracing::info!(path = ?path, "Removed stale upload session file"),
+                    Err(e) => {
+                        tracing::error!(path = ?path, error = ?e, "Error removing stale upload session file")
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Starts a resumable upload, returning its id. Pieces of the file are then sent with `PATCH` to
+/// `{route}/session/{id}`, and the upload is finished with a `POST` to
+/// `{route}/session/{id}/complete`.
+#[tracing::instrument(skip(config, sessions))]
+async fn create_session(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(sessions): Extension<Arc<Sessions>>,
+) -> Result<(StatusCode, String), ApiError> {
+    let id = Uuid::new_v4().simple().to_string();
+    let path = config.target_dir.join(format!(".session-{id}.part"));
+    create_new_file(&path).await.map_err(|e| {
+        tracing::error!(path = ?path, error = ?e, "Error creating upload session file");
+        ApiError::internal("Could not create the upload session")
+    })?;
+
+    let session = Session {
+        path,
+        size: 0,
+        total: None,
+        last_active: Instant::now(),
+    };
+    sessions
+        .0
+        .lock()
+        .unwrap()
+        .insert(id.clone(), Arc::new(tokio::sync::Mutex::new(session)));
+    tracing::info!("Started upload session {id}");
+    Ok((StatusCode::CREATED, id))
+}
+
+/// Returns how many bytes of a session have been received, which is where it has to be resumed.
+#[tracing::instrument(skip(sessions))]
+async fn session_offset(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(sessions): Extension<Arc<Sessions>>,
+    Path(id): Path<String>,
+) -> Result<String, ApiError> {
+    let session = sessions.get(&id)?;
+    let session = session.lock().await;
+    Ok(session.size.to_string())
+}
+
+/// A parsed `Content-Range: bytes {start}-{end}/{total}` header, where the total may be `*` if it
+/// isn't known yet.
+struct ContentRange {
+    start: u64,
+    end: u64,
+    total: Option<u64>,
+}
+
+fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let range = ContentRange {
+        start: start.trim().parse().ok()?,
+        end: end.trim().parse().ok()?,
+        total: match total.trim() {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        },
+    };
+    let valid = range.start <= range.end && range.total.is_none_or(|total| range.end < total);
+    valid.then_some(range)
+}
+
+/// Appends the body to a session, returning the new number of bytes received. With a
+/// `Content-Range` header, its start has to be where the session currently ends, and the body may
+/// not extend past its end. Without one, the body is just appended.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(headers, body, config, uploads, sessions, usage))]
+async fn append_to_session(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(uploads): Extension<Arc<Semaphore>>,
+    Extension(sessions): Extension<Arc<Sessions>>,
+    Extension(usage): Extension<Arc<DiskUsage>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    mut body: Body,
+) -> Result<String, ApiError> {
+    let range = match headers.get(header::CONTENT_RANGE) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(parse_content_range)
+                .ok_or_else(|| ApiError::bad_request("Invalid Content-Range header"))?,
+        ),
+        None => None,
+    };
+
+    let Ok(_permit) = uploads.try_acquire() else {
+        tracing::warn!("Rejecting upload, too many in progress");
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many uploads in progress, try again later",
+        ));
+    };
+
+    let session = sessions.get(&id)?;
+    let Ok(mut session) = session.try_lock() else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "The upload session is already being written to",
+        ));
+    };
+    session.last_active = Instant::now();
+
+    if let Some(range) = &range {
+        if range.start != session.size {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                format!(
+                    "The range has to start at {}, where the upload currently ends",
+                    session.size
+                ),
+            ));
+        }
+        if let Some(total) = range.total {
+            if session.total.is_some_and(|known| known != total) {
+                return Err(ApiError::bad_request(
+                    "The total size differs from the one sent before",
+                ));
+            }
+            if config.max_bytes.is_some_and(|max| total > max as u64) {
+                return Err(ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "The file is larger than allowed",
+                ));
+            }
+            session.total = Some(total);
+        }
+    }
+    // Where the body has to end, exclusively.
+    let limit = range.as_ref().map(|range| range.end + 1);
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(&session.path)
+        .await
+        .map_err(|e| {
+            tracing::error!(path = ?session.path, error = ?e, "Error opening upload session file");
+            ApiError::internal("Could not write the file")
+        })?;
+
+    let span = tracing::info_span!("Appending to upload session", path = ?session.path);
+    // Everything written before an error is kept, so the client can resume from there.
+    let result = async {
+        while let Some(frame) =
+            std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_frame(cx)).await
+        {
+            let frame = frame.map_err(|e| {
+                tracing::warn!(error = ?e, "Error reading upload body");
+                ApiError::bad_request("Could not read the request body")
             })?;
+            let Ok(chunk) = frame.into_data() else {
+                continue;
+            };
+
+            let new_size = session.size + chunk.len() as u64;
+            if limit.is_some_and(|limit| new_size > limit) {
+                return Err(ApiError::bad_request(
+                    "The body is longer than its Content-Range",
+                ));
+            }
+            if session.total.is_some_and(|total| new_size > total)
+                || config.max_bytes.is_some_and(|max| new_size > max as u64)
+            {
+                return Err(ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "The file is larger than allowed",
+                ));
+            }
+
+            usage.reserve(chunk.len() as u64)?;
+            if let Err(e) = file.write_all(&chunk).await {
+                tracing::error!(error = ?e, "Error writing file");
+                usage.release(chunk.len() as u64);
+                return Err(ApiError::internal("Could not write the file"));
+            }
+            session.size = new_size;
+        }
+        file.flush().await.map_err(|e| {
+            tracing::error!(error = ?e, "Error writing file");
+            ApiError::internal("Could not write the file")
+        })
+    }
+    .instrument(span)
+    .await;
+    session.last_active = Instant::now();
+    result?;
+
+    tracing::info!("Upload session {id} is at {} bytes", session.size);
+    Ok(session.size.to_string())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CompleteSessionRequest {
+    /// Name of the uploaded file, used like the file name of a multipart upload.
+    file_name: Option<String>,
+}
+
+/// Moves a session's file into place under its final name, which is returned like for a
+/// multipart upload. If the client said how large the file is, all of it has to have arrived.
+#[tracing::instrument(skip(config, sessions))]
+async fn complete_session(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(sessions): Extension<Arc<Sessions>>,
+    Path(id): Path<String>,
+    Json(req): Json<CompleteSessionRequest>,
+) -> Result<String, ApiError> {
+    let session = sessions.get(&id)?;
+    let Ok(session) = session.try_lock() else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "The upload session is still being written to",
+        ));
+    };
+    if let Some(total) = session.total {
+        if session.size != total {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                format!("Only {} of {total} bytes have been uploaded", session.size),
+            ));
+        }
+    }
+
+    let original_name = req.file_name.as_deref().map(sanitize_filename);
+    // This only reserves the name, the session's file is moved over it below.
+    let NewUpload {
+        name,
+        extension,
+        path,
+        part_path,
+        file,
+    } = create_upload(&config, original_name.as_deref()).await?;
+    drop(file);
+
+    let written = async {
+        if !config.allowed_extensions.is_empty() {
+            let mut head = Vec::new();
+            File::open(&session.path)
+                .await
+                .map_err(|e| {
+                    tracing::error!(path = ?session.path, error = ?e, "Error opening upload session file");
+                    ApiError::internal("Could not read the file")
+                })?
+                .take(SNIFF_BYTES as u64)
+                .read_to_end(&mut head)
+                .await
+                .map_err(|e| {
+                    tracing::error!(path = ?session.path, error = ?e, "Error reading upload session file");
+                    ApiError::internal("Could not read the file")
+                })?;
+            check_content_type(&head, &extension, config.strict_content_type)?;
+        }
+        tokio::fs::rename(&session.path, &part_path)
+            .await
+            .map_err(|e| {
+                tracing::error!(path = ?session.path, error = ?e, "Error moving upload session file");
+                ApiError::internal("Could not write the file")
+            })
+    }
+    .await;
+    finish_upload(&part_path, &path, written).await?;
 
-        tracing::info!(path = ?path, "Uploaded file");
+    let size = session.size;
+    drop(session);
+    sessions.0.lock().unwrap().remove(&id);
+    tracing::info!(
+        path = ?path,
+        "Completed upload session {id} as {} with {size} bytes",
+        original_name.as_deref().unwrap_or("without a name"),
+    );
+    Ok(public_name(&config, name))
+}
+
+/// How much of the start of an upload is looked at to detect its type.
+const SNIFF_BYTES: usize = 8192;
 
-        return Ok(name);
+/// Checks that the content of a file, going by its first bytes, matches its `extension`. Content of
+/// unknown type is only rejected if `strict`.
+fn check_content_type(head: &[u8], extension: &str, strict: bool) -> Result<(), ApiError> {
+    let extension = extension.to_ascii_lowercase();
+    let Some(detected) = infer::get(head) else {
+        if strict {
+            tracing::warn!("Rejecting upload with extension {extension} of unknown content type");
+            return Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "The type of the file could not be detected",
+            ));
+        }
+        return Ok(());
+    };
+
+    // infer only knows one extension per type.
+    let normalized = match extension.as_str() {
+        "jpeg" | "jpe" => "jpg",
+        "tiff" => "tif",
+        "htm" => "html",
+        "mpeg" => "mpg",
+        "oga" => "ogg",
+        other => other,
+    };
+    if normalized != detected.extension() {
+        tracing::warn!(
+            "Rejecting upload with extension {extension} but detected type {} ({})",
+            detected.mime_type(),
+            detected.extension()
+        );
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!(
+                "The file looks like {}, which doesn't match its extension {extension:?}",
+                detected.mime_type()
+            ),
+        ));
     }
+    Ok(())
 }
 
-async fn get_file_name_and_bytes(mut body: Multipart) -> Result<(String, Bytes), StatusCode> {
-    let field = body
-        .next_field()
+#[derive(Debug, serde::Serialize)]
+struct UploadedFile {
+    name: String,
+    size: u64,
+    modified: String,
+}
+
+#[tracing::instrument(skip(config))]
+async fn list(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<Json<Vec<UploadedFile>>, ApiError> {
+    tracing::info!("List uploads request");
+
+    let internal_error = |e: std::io::Error| {
+        tracing::error!(error = ?e, "Error listing upload target dir");
+        ApiError::internal("Could not list the uploads")
+    };
+
+    let mut files = Vec::new();
+    for (name, _, metadata) in walk_files(&config.target_dir)
         .await
-        .map_err(|_| StatusCode::BAD_REQUEST)?
-        .ok_or(StatusCode::BAD_REQUEST)?;
+        .map_err(internal_error)?
+    {
+        if is_hidden(&name) {
+            continue;
+        }
+        let modified = metadata.modified().map_err(internal_error)?;
+
+        files.push(UploadedFile {
+            name,
+            size: metadata.len(),
+            modified: DateTime::<Local>::from(modified).to_rfc3339(),
+        });
+    }
+
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(files))
+}
+
+/// Deletes an uploaded file, along with its cached thumbnail.
+#[tracing::instrument(skip(config, usage))]
+async fn delete(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(usage): Extension<Arc<DiskUsage>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let path = uploaded_file_path(&config, &name)?;
+    let size = tokio::fs::metadata(&path).await.map_or(0, |m| m.len());
+
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => {
+            tracing::info!(path = ?path, %client_addr, "Deleted uploaded file");
+            if !is_hidden(&name) {
+                usage.release(size);
+            }
+            // There may be no thumbnail, which is fine.
+            let _ = tokio::fs::remove_file(thumbnail_path(&config, &name)).await;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(StatusCode::NOT_FOUND.into()),
+        Err(e) => {
+            tracing::error!(path = ?path, error = ?e, "Error deleting uploaded file");
+            Err(ApiError::internal("Could not delete the file"))
+        }
+    }
+}
+
+#[tracing::instrument(skip(config))]
+async fn thumbnail(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Path(name): Path<String>,
+) -> Result<Response, ApiError> {
+    let path = uploaded_file_path(&config, &name)?;
+
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    let format = match extension.as_deref() {
+        Some("png") => ImageFormat::Png,
+        Some("jpg" | "jpeg") => ImageFormat::Jpeg,
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Thumbnails are only available for PNG and JPEG images",
+            ))
+        }
+    };
+
+    uploaded_file_metadata(&path).await?;
+
+    // Decoding and resizing is CPU-heavy, so it mustn't block the async runtime.
+    let thumb_path = thumbnail_path(&config, &name);
+    let max_size = config.thumbnail_size;
+    let thumbnail = tokio::task::spawn_blocking(move || {
+        load_or_make_thumbnail(&path, &thumb_path, format, max_size)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!(error = ?e, "Thumbnail task failed");
+        ApiError::internal("Could not create a thumbnail")
+    })?
+    .map_err(|e| {
+        tracing::error!("Could not create thumbnail: {e:?}");
+        ApiError::internal("Could not create a thumbnail")
+    })?;
+
+    Ok(([(header::CONTENT_TYPE, format.to_mime_type())], thumbnail).into_response())
+}
+
+/// Deletes all files in the target dir and its subdirectories, including cached thumbnails, last
+/// modified more than `days` days ago.
+async fn delete_expired(config: &Config, usage: &DiskUsage, days: u32) {
+    let Some(cutoff) =
+        SystemTime::now().checked_sub(Duration::from_secs(u64::from(days) * 24 * 60 * 60))
+    else {
+        return;
+    };
+
+    let files = match walk_files(&config.target_dir).await {
+        Ok(files) => files,
+        Err(e) => {
+            tracing::error!(error = ?e, "Error listing upload target dir for cleanup");
+            return;
+        }
+    };
+
+    for (name, path, metadata) in files {
+        match metadata.modified() {
+            Ok(modified) if modified < cutoff => {}
+            Ok(_) => continue,
+            Err(e) => {
+                tracing::warn!(path = ?path, error = ?e, "Could not check age of uploaded file");
+                continue;
+            }
+        }
+
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {
+                tracing::info!(path = ?path, "Deleted upload older than {days} days");
+                if !is_hidden(&name) {
+                    usage.release(metadata.len());
+                }
+            }
+            Err(e) => tracing::error!(path = ?path, error = ?e, "Error deleting expired upload"),
+        }
+    }
+}
+
+/// Whether the file `name`, relative to the target dir, is one of our own files, like thumbnails or
+/// uploads in progress, rather than an upload.
+fn is_hidden(name: &str) -> bool {
+    name.rsplit('/').next().unwrap_or_default().starts_with('.')
+}
+
+/// The total size of the uploads in the target dir, counting those still being written, to keep
+/// it under `max_total_bytes`. Only tracked if that is set.
+struct DiskUsage {
+    used: AtomicU64,
+    max: Option<u64>,
+}
+
+impl DiskUsage {
+    /// Adds up the size of the uploads that are already there.
+    fn new(config: &Config) -> miette::Result<Self> {
+        let used = match config.max_total_bytes {
+            Some(_) => dir_size(&config.target_dir)
+                .into_diagnostic()
+                .wrap_err("Failed to compute size of upload target dir")?,
+            None => 0,
+        };
+        if let Some(max) = config.max_total_bytes {
+            tracing::info!("Uploads use {used} of {max} bytes");
+        }
+        Ok(Self {
+            used: AtomicU64::new(used),
+            max: config.max_total_bytes,
+        })
+    }
+
+    /// Counts `bytes` more, unless that would go over the limit.
+    fn reserve(&self, bytes: u64) -> Result<(), ApiError> {
+        let Some(max) = self.max else {
+            return Ok(());
+        };
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&new| new <= max)
+            })
+            .map(|_| ())
+            .map_err(|used| {
+                tracing::warn!("Rejecting upload, {used} of {max} bytes are used already");
+                ApiError::new(
+                    StatusCode::INSUFFICIENT_STORAGE,
+                    "There is no space left for uploads",
+                )
+            })
+    }
+
+    fn release(&self, bytes: u64) {
+        if self.max.is_some() {
+            // Uploads changed behind our back may make this a bit off, but it must never wrap.
+            let _ = self
+                .used
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                    Some(used.saturating_sub(bytes))
+                });
+        }
+    }
+}
+
+/// The total size of the uploads in `dir` and its subdirectories. Blocks, so it's only used at
+/// startup.
+fn dir_size(dir: &std::path::Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Lists all files in `dir` and its subdirectories, with their names relative to `dir`.
+async fn walk_files(
+    dir: &std::path::Path,
+) -> std::io::Result<Vec<(String, PathBuf, std::fs::Metadata)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(String::new(), dir.to_path_buf())];
+    while let Some((prefix, dir)) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let name = format!("{prefix}{name}");
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() {
+                dirs.push((format!("{name}/"), entry.path()));
+            } else if metadata.is_file() {
+                files.push((name, entry.path(), metadata));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Serves an uploaded file, with a `Content-Type` guessed from its extension.
+#[tracing::instrument(skip(config))]
+async fn file(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Path(name): Path<String>,
+) -> Result<Response, ApiError> {
+    let path = uploaded_file_path(&config, &name)?;
+    let file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(StatusCode::NOT_FOUND.into()),
+        Err(e) => {
+            tracing::error!(path = ?path, error = ?e, "Error opening uploaded file");
+            return Err(ApiError::internal("Could not read the file"));
+        }
+    };
+    let metadata = file.metadata().await.map_err(|e| {
+        tracing::error!(path = ?path, error = ?e, "Error checking uploaded file");
+        ApiError::internal("Could not read the file")
+    })?;
+    if !metadata.is_file() {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    Ok((
+        file_headers(&name, metadata.len()),
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response())
+}
+
+/// Like `file`, but only sends the headers.
+#[tracing::instrument(skip(config))]
+async fn file_head(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(config): Extension<Arc<Config>>,
+    Path(name): Path<String>,
+) -> Result<Response, ApiError> {
+    let path = uploaded_file_path(&config, &name)?;
+    let metadata = uploaded_file_metadata(&path).await?;
+    Ok(file_headers(&name, metadata.len()).into_response())
+}
+
+/// Uploads are served from the same origin as everything else, so only types a browser won't run
+/// scripts from are shown inline. Anything else, like HTML or SVG, is sent as a download.
+fn file_headers(name: &str, len: u64) -> [(header::HeaderName, String); 4] {
+    let content_type = mime_guess::from_path(name).first_or_octet_stream();
+    let is_safe = match content_type.type_() {
+        mime_guess::mime::IMAGE => content_type.subtype() != mime_guess::mime::SVG,
+        mime_guess::mime::AUDIO | mime_guess::mime::VIDEO => true,
+        mime_guess::mime::TEXT => content_type.subtype() == mime_guess::mime::PLAIN,
+        _ => false,
+    };
+    [
+        (header::CONTENT_TYPE, content_type.to_string()),
+        (header::CONTENT_LENGTH, len.to_string()),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            if is_safe { "inline" } else { "attachment" }.to_string(),
+        ),
+    ]
+}
+
+/// Returns the metadata of an uploaded file, or a 404 if there is no such file.
+async fn uploaded_file_metadata(path: &std::path::Path) -> Result<std::fs::Metadata, ApiError> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_file() => Ok(metadata),
+        Ok(_) => Err(StatusCode::NOT_FOUND.into()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(StatusCode::NOT_FOUND.into()),
+        Err(e) => {
+            tracing::error!(path = ?path, error = ?e, "Error checking uploaded file");
+            Err(ApiError::internal("Could not read the file"))
+        }
+    }
+}
+
+/// Where the thumbnail of the uploaded file `name` is cached. Dotfiles don't show up in `list`.
+fn thumbnail_path(config: &Config, name: &str) -> PathBuf {
+    let (subdir, file_name) = name.rsplit_once('/').unwrap_or(("", name));
+    config
+        .target_dir
+        .join(subdir)
+        .join(format!(".thumb.{file_name}"))
+}
+
+/// Returns the thumbnail of the image at `path`, reusing the one at `thumb_path` unless the image
+/// has changed since it was made. Blocks, so it should run on the blocking thread pool.
+fn load_or_make_thumbnail(
+    path: &std::path::Path,
+    thumb_path: &std::path::Path,
+    format: ImageFormat,
+    max_size: u32,
+) -> miette::Result<Vec<u8>> {
+    let modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .into_diagnostic()
+        .context("read image modification time")?;
+    if let Ok(thumb_modified) = std::fs::metadata(thumb_path).and_then(|m| m.modified()) {
+        if thumb_modified >= modified {
+            return std::fs::read(thumb_path)
+                .into_diagnostic()
+                .context("read cached thumbnail");
+        }
+    }
 
-    let field_name = field.name();
-    if field_name != Some("file") {
+    let image = ImageReader::open(path)
+        .into_diagnostic()
+        .context("open image")?
+        .with_guessed_format()
+        .into_diagnostic()
+        .context("guess image format")?
+        .decode()
+        .into_diagnostic()
+        .context("decode image")?;
+
+    let mut thumbnail = Vec::new();
+    image
+        .thumbnail(max_size, max_size)
+        .write_to(&mut Cursor::new(&mut thumbnail), format)
+        .into_diagnostic()
+        .context("encode thumbnail")?;
+
+    if let Err(e) = std::fs::write(thumb_path, &thumbnail) {
+        tracing::warn!(path = ?thumb_path, error = ?e, "Could not cache thumbnail");
+    }
+    Ok(thumbnail)
+}
+
+/// Resolves a file name supplied by a client, which may include subdirectories separated by `/`,
+/// to a path in the upload target dir. Names that could point anywhere else, or at our own
+/// dotfiles like thumbnails and uploads in progress, are rejected.
+fn uploaded_file_path(config: &Config, name: &str) -> Result<PathBuf, StatusCode> {
+    if name
+        .split('/')
+        .any(|part| part.is_empty() || part.starts_with('.'))
+        || name.contains(['\\', '\0'])
+        || name.contains("..")
+    {
+        tracing::warn!("Rejecting unsafe file name {name:?}");
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    let file_name = field
-        .file_name()
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_string();
-    let bytes = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(config.target_dir.join(name))
+}
+
+/// Reduces a client-supplied file name to its final path segment, so it can't point outside of the
+/// target dir. Returns an empty string if nothing usable is left, which includes dotfiles since
+/// those names are used for our own files.
+fn sanitize_filename(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = name.replace('\0', "");
+    if name.starts_with('.') {
+        return String::new();
+    }
+    name
+}
+
+/// Creates the temporary file an upload to `path` is written to, and returns it along with its
+/// path. Fails with `AlreadyExists` if either the temporary file or `path` itself already exists,
+/// so concurrent uploads of the same name can't overwrite each other.
+async fn create_part_file(path: &std::path::Path) -> std::io::Result<(PathBuf, File)> {
+    if tokio::fs::try_exists(path).await? {
+        return Err(ErrorKind::AlreadyExists.into());
+    }
+    let part_path = part_path(path);
+    let file = create_new_file(&part_path).await?;
+    Ok((part_path, file))
+}
+
+/// Where an upload to `path` is written to until it's complete. Dotfiles don't show up in `list`.
+fn part_path(path: &std::path::Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{file_name}.part"))
+}
+
+async fn create_new_file(path: &std::path::Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await
+}
+
+/// Returns the next field of the upload, or `None` once there are no more. All fields have to be
+/// named `file`.
+async fn next_file_field(body: &mut Multipart) -> Result<Option<Field<'_>>, ApiError> {
+    let Some(field) = body.next_field().await.map_err(|e| {
+        tracing::warn!(error = ?e, "Error reading upload body");
+        ApiError::new(e.status(), e.body_text())
+    })?
+    else {
+        return Ok(None);
+    };
 
-    tracing::info!("Got file {} with {} bytes", file_name, bytes.len());
-    Ok((file_name, bytes))
+    if field.name() != Some("file") {
+        return Err(ApiError::bad_request(
+            "Expected only multipart fields named \"file\"",
+        ));
+    }
+
+    Ok(Some(field))
 }
 
-fn generate_name(len: usize) -> String {
+fn generate_name(len: usize, alphabet: Option<&str>) -> String {
     fn num_to_char(num: usize) -> char {
         match num {
             0..=25 => (b'a' + num as u8) as char,
@@ -136,7 +1463,240 @@ fn generate_name(len: usize) -> String {
 
     use rand::prelude::*;
     let mut rng = thread_rng();
+
+    // An empty name would leave just the extension. `validate` rules this out already.
+    let len = len.max(1);
+
+    if let Some(alphabet) = alphabet {
+        let chars = alphabet.chars().collect::<Vec<_>>();
+        return (0..len)
+            .map(|_| *chars.choose(&mut rng).expect("alphabet is not empty"))
+            .collect();
+    }
+
     (0..len)
         .map(|_| num_to_char(rng.gen_range(0..=61)))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A config with the defaults, uploading to `target_dir`.
+    fn test_config(target_dir: PathBuf) -> Config {
+        Config {
+            enabled: true,
+            route: "/upload".to_string(),
+            target_dir,
+            create_target_dir: false,
+            filename_length: 4,
+            keep_name: false,
+            default_extension: None,
+            auth_token: None,
+            basic_auth: None,
+            allowed_extensions: Vec::new(),
+            strict_content_type: false,
+            public_base_url: None,
+            alphabet: None,
+            max_bytes: None,
+            max_total_bytes: None,
+            subdir_format: None,
+            max_concurrent_uploads: 4,
+            thumbnail_size: 256,
+            retention_days: None,
+            session_ttl_seconds: 3600,
+        }
+    }
+
+    /// A fresh, empty directory to upload to, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("upload-test-{}", Uuid::new_v4()));
+            std::fs::create_dir(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Uploads `content` in a single multipart field named `file`, sent without a file name.
+    async fn post_without_file_name(config: Config, content: &str) -> Result<String, ApiError> {
+        use axum::extract::FromRequest;
+
+        let body = format!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"file\"\r\n\r\n\
+             {content}\r\n\
+             --boundary--\r\n"
+        );
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let multipart = Multipart::from_request(request, &()).await.unwrap();
+
+        let usage = DiskUsage::new(&config).unwrap();
+        post(
+            ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))),
+            Extension(Arc::new(config)),
+            Extension(Arc::new(Semaphore::new(1))),
+            Extension(Arc::new(usage)),
+            multipart,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn file_without_name_gets_generated_name() {
+        let dir = TempDir::new();
+        let mut config = test_config(dir.0.clone());
+        config.default_extension = Some("bin".to_string());
+
+        let name = post_without_file_name(config, "hello").await.unwrap();
+
+        let (stem, extension) = name.split_once('.').unwrap();
+        assert_eq!(stem.len(), 4);
+        assert!(stem.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_eq!(extension, "bin");
+        assert_eq!(std::fs::read_to_string(dir.0.join(&name)).unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn file_without_name_is_rejected_without_default_extension() {
+        let dir = TempDir::new();
+        let config = test_config(dir.0.clone());
+
+        let error = post_without_file_name(config, "hello").await.unwrap_err();
+
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn files_named_like_routes_can_be_deleted() {
+        use axum::http::{Method, Request};
+        use tower::ServiceExt;
+
+        let dir = TempDir::new();
+        let app = setup(
+            test_config(dir.0.clone()),
+            CancellationToken::new(),
+            Router::new(),
+        )
+        .unwrap();
+
+        for name in ["list", "session"] {
+            std::fs::write(dir.0.join(name), "hello").unwrap();
+            let mut request = Request::builder()
+                .method(Method::DELETE)
+                .uri(format!("/upload/file/{name}"))
+                .body(Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+
+            let response = app.clone().oneshot(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::NO_CONTENT, "{name}");
+            assert!(!dir.0.join(name).exists(), "{name}");
+        }
+    }
+
+    #[test]
+    fn validate_rejects_filename_length_outside_range() {
+        let mut config = test_config(std::env::temp_dir());
+        for length in [0, MAX_FILENAME_LENGTH + 1] {
+            config.filename_length = length;
+            let error = validate(&config).unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .contains("filename-length must be between"),
+                "{error}"
+            );
+        }
+
+        config.filename_length = 1;
+        validate(&config).unwrap();
+        config.filename_length = MAX_FILENAME_LENGTH;
+        validate(&config).unwrap();
+    }
+
+    #[test]
+    fn sanitize_filename_keeps_last_segment() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("..\\secret.txt"), "secret.txt");
+        assert_eq!(sanitize_filename("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("a\0b.png"), "ab.png");
+        assert_eq!(sanitize_filename("photo.png"), "photo.png");
+    }
+
+    #[test]
+    fn sanitize_filename_rejects_unusable_names() {
+        assert_eq!(sanitize_filename(""), "");
+        assert_eq!(sanitize_filename("."), "");
+        assert_eq!(sanitize_filename(".."), "");
+        assert_eq!(sanitize_filename("dir/.."), "");
+        assert_eq!(sanitize_filename(".thumb.photo.png"), "");
+        assert_eq!(sanitize_filename(".photo.png.part"), "");
+        assert_eq!(sanitize_filename(".session-abcd.part"), "");
+        assert_eq!(sanitize_filename("dir/.hidden"), "");
+        assert_eq!(sanitize_filename("\0.hidden"), "");
+    }
+
+    #[test]
+    fn uploaded_file_path_rejects_unsafe_names() {
+        let config = test_config(PathBuf::from("/uploads"));
+        for name in [
+            "../etc/passwd",
+            "a/../../b",
+            "/etc/passwd",
+            "a\0b.png",
+            "",
+            ".",
+            "..",
+            ".thumb.photo.png",
+            "2024/.photo.png.part",
+            ".session-abcd.part",
+            ".2024/photo.png",
+        ] {
+            assert_eq!(
+                uploaded_file_path(&config, name),
+                Err(StatusCode::BAD_REQUEST),
+                "{name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn file_headers_only_show_safe_types_inline() {
+        let disposition = |name| file_headers(name, 0)[3].1.clone();
+        assert_eq!(disposition("a.png"), "inline");
+        assert_eq!(disposition("a.txt"), "inline");
+        assert_eq!(disposition("a.html"), "attachment");
+        assert_eq!(disposition("a.svg"), "attachment");
+        assert_eq!(disposition("a"), "attachment");
+        assert_eq!(file_headers("a.png", 0)[2].1, "nosniff");
+    }
+
+    #[test]
+    fn uploaded_file_path_allows_subdirs() {
+        let config = test_config(PathBuf::from("/uploads"));
+        assert_eq!(
+            uploaded_file_path(&config, "2024/05/abcd.png"),
+            Ok(PathBuf::from("/uploads/2024/05/abcd.png"))
+        );
+    }
+}